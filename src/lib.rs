@@ -1,18 +1,33 @@
 extern crate shlex;
 extern crate nix;
+#[cfg(feature = "hashing")]
+extern crate sha2;
+#[cfg(feature = "regex")]
+extern crate regex;
+extern crate zeroize;
 
 #[cfg(unix)]
 use std::process::Child;
 use std::process::{Command, Stdio};
-use std::io;
+use std::io::{self, Write};
+use std::thread;
 use std::fmt;
 use std::fs::File;
-use std::path::PathBuf;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::ffi::{OsStr, OsString};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
 use std::time::Duration;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[cfg(unix)]
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
 #[cfg(windows)]
 use std::os::windows::io::{FromRawHandle, IntoRawHandle};
 
@@ -26,17 +41,136 @@ use nix::unistd::{dup, pipe2};
 #[cfg(unix)]
 use nix::sys::termios;
 #[cfg(unix)]
-use nix::fcntl::{fcntl, FD_CLOEXEC, O_CLOEXEC};
+use nix::fcntl::{fcntl, FdFlag, FD_CLOEXEC, O_CLOEXEC};
 #[cfg(unix)]
 use nix::fcntl::FcntlArg::F_SETFD;
 
 pub mod running;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub struct Runny {
     cmd: String,
+    argv: Option<Vec<OsString>>,
+    resolved_program: Option<PathBuf>,
+    env_expansion: bool,
+    arg_env: Option<String>,
     working_directory: Option<PathBuf>,
+    working_directory_env: Option<String>,
     timeout: Option<Duration>,
+    retries: Option<(u32, Duration)>,
+    shutdown_delay: Option<Duration>,
     path: Vec<PathBuf>,
+    default_path: bool,
+    input_filter: Option<running::InputFilter>,
+    stdin_line_ending: running::LineEnding,
+    stdout_line_ending: running::LineEnding,
+    env_secrets: Vec<(String, SecretString)>,
+    #[cfg(unix)]
+    process_name: Option<OsString>,
+    #[cfg(unix)]
+    umask: Option<u32>,
+    #[cfg(unix)]
+    coredump_enabled: Option<bool>,
+    #[cfg(unix)]
+    forwarded_signals: Vec<nix::sys::signal::Signal>,
+    #[cfg(unix)]
+    pty_echo: bool,
+    #[cfg(unix)]
+    pty_size_from_env: Option<(u16, u16)>,
+    #[cfg(unix)]
+    stdio_sockets: bool,
+    #[cfg(unix)]
+    stdio_unix_socket: Option<PathBuf>,
+    #[cfg(unix)]
+    activation_fds: Vec<RawFd>,
+    #[cfg(unix)]
+    stdio_timeout: Option<(Duration, Duration)>,
+    #[cfg(unix)]
+    fd_passing: bool,
+    #[cfg(unix)]
+    notify_socket: bool,
+    #[cfg(unix)]
+    syslog: Option<(String, nix::libc::c_int)>,
+    #[cfg(target_os = "linux")]
+    pipe_limit: Option<usize>,
+    #[cfg(target_os = "linux")]
+    pipe_flags: nix::fcntl::OFlag,
+    #[cfg(target_os = "linux")]
+    namespaces: nix::sched::CloneFlags,
+    #[cfg(target_os = "linux")]
+    loopback: bool,
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    count_syscalls: bool,
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    perf_events: Vec<running::PerfEvent>,
+    #[cfg(target_os = "linux")]
+    tmpfs_mounts: Vec<(PathBuf, String)>,
+    #[cfg(target_os = "linux")]
+    container_init: bool,
+    #[cfg(target_os = "linux")]
+    parent_death_signal: Option<nix::sys::signal::Signal>,
+    #[cfg(target_os = "linux")]
+    oom_score_adj: Option<i16>,
+    #[cfg(target_os = "linux")]
+    sysfs_writes: Vec<(String, String)>,
+    #[cfg(target_os = "linux")]
+    scheduler: Option<(SchedPolicy, u32)>,
+    #[cfg(target_os = "linux")]
+    supplementary_groups: Option<Vec<u32>>,
+}
+
+/// A value, such as a password, that shouldn't end up in logs.  Its Debug
+/// and Display output is redacted, and its memory is zeroed when it's
+/// dropped.
+pub struct SecretString(zeroize::Zeroizing<String>);
+
+impl SecretString {
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(zeroize::Zeroizing::new(s))
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+/// Linux scheduling policies that may be given to Runny::with_scheduler().
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedPolicy {
+    Normal,
+    Batch,
+    Idle,
+    Fifo,
+    RoundRobin,
+}
+
+#[cfg(target_os = "linux")]
+impl SchedPolicy {
+    fn as_raw(self) -> nix::libc::c_int {
+        match self {
+            SchedPolicy::Normal => nix::libc::SCHED_OTHER,
+            SchedPolicy::Batch => nix::libc::SCHED_BATCH,
+            SchedPolicy::Idle => nix::libc::SCHED_IDLE,
+            SchedPolicy::Fifo => nix::libc::SCHED_FIFO,
+            SchedPolicy::RoundRobin => nix::libc::SCHED_RR,
+        }
+    }
 }
 
 pub enum RunnyError {
@@ -44,6 +178,22 @@ pub enum RunnyError {
     NoCommandSpecified,
     #[cfg(unix)]
     NixError(nix::Error),
+    /// The command didn't finish in time, and was killed.  This holds
+    /// whatever it wrote up until then.
+    TimedOut(running::Output),
+    /// The variable passed to with_arg_env() couldn't be split into arguments.
+    InvalidArgEnv(String),
+}
+
+impl fmt::Debug for Runny {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Runny")
+            .field("cmd", &self.cmd)
+            .field("working_directory", &self.working_directory)
+            .field("timeout", &self.timeout)
+            .field("env_secrets", &self.env_secrets)
+            .finish()
+    }
 }
 
 impl fmt::Debug for RunnyError {
@@ -53,6 +203,8 @@ impl fmt::Debug for RunnyError {
             &RunnyError::NoCommandSpecified => write!(f, "No command was specified"),
             #[cfg(unix)]
             &RunnyError::NixError(ref e) => write!(f, "Nix library error: {:?}", e),
+            RunnyError::TimedOut(o) => write!(f, "Timed out, with output {:?}", o),
+            RunnyError::InvalidArgEnv(var) => write!(f, "Unable to parse arguments from ${}", var),
         }
     }
 }
@@ -70,317 +222,3714 @@ impl From<nix::Error> for RunnyError {
     }
 }
 
-impl Runny {
-    pub fn new(cmd: &str) -> Runny {
-        Runny {
-            cmd: cmd.to_string(),
-            working_directory: None,
-            timeout: None,
-            path: vec![],
+#[cfg(test)]
+thread_local! {
+    /// Variables set by tests for the current thread only, since changing the
+    /// real environment races with other tests spawning children.
+    static TEST_ENV: std::cell::RefCell<HashMap<String, Option<String>>> =
+        std::cell::RefCell::new(HashMap::new());
+}
+
+/// Look up the environment variable `name`.
+fn env_var(name: &str) -> Option<OsString> {
+    #[cfg(test)]
+    {
+        if let Some(value) = TEST_ENV.with(|vars| vars.borrow().get(name).cloned()) {
+            return value.map(OsString::from);
         }
     }
+    env::var_os(name)
+}
 
-    pub fn directory(&mut self, wd: &Option<PathBuf>) -> &mut Runny {
-        self.working_directory = wd.clone();
-        self
+#[cfg(unix)]
+fn nix_to_io(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => io::Error::from(errno),
+        _ => io::Error::from_raw_os_error(nix::libc::EINVAL),
     }
+}
 
-    pub fn path(&mut self, path: Vec<PathBuf>) -> &mut Runny {
-        self.path = path;
-        self
-    }
+/// Enter a new mount namespace, with propagation back to the host turned off,
+/// and mount a tmpfs at each path with the given options.
+#[cfg(target_os = "linux")]
+fn mount_tmpfs(mounts: &[(PathBuf, String)]) -> io::Result<()> {
+    use nix::mount::{mount, MsFlags, MS_PRIVATE, MS_REC};
+    use nix::sched::{unshare, CLONE_NEWNS};
 
-    pub fn timeout(&mut self, timeout: Duration) -> &mut Runny {
-        self.timeout = Some(timeout);
-        self
+    unshare(CLONE_NEWNS).map_err(nix_to_io)?;
+    mount(None::<&str>, "/", None::<&str>, MS_REC | MS_PRIVATE, None::<&str>).map_err(nix_to_io)?;
+    for (path, options) in mounts {
+        mount(Some("tmpfs"),
+              path.as_path(),
+              Some("tmpfs"),
+              MsFlags::empty(),
+              Some(options.as_str())).map_err(nix_to_io)?;
     }
+    Ok(())
+}
 
-    /// Spawn a new process connected to the slave TTY
-    #[cfg(unix)]
-    fn spawn(&self,
-             mut cmd: Command,
-             slave_fd: i32,
-             handles: &mut HashMap<String, File>)
-             -> Result<Child, RunnyError> {
-
-        // When reading from the pty, sometimes we get -EIO or -EBADF,
-        // which can be ignored.  But Rust really doesn't like this.
-        // So send the pty through a pipe, and ignore those errors.
-        //
-        let (stderr_rx, stderr_tx) = pipe2(O_CLOEXEC)?;
-        fcntl(stderr_rx, F_SETFD(FD_CLOEXEC))?;
+/// Set the soft RLIMIT_CORE to unlimited if `enabled`, or to 0 otherwise.
+#[cfg(unix)]
+fn set_core_limit(enabled: bool) -> io::Result<()> {
+    use nix::libc;
 
-        let stderr = unsafe { File::from_raw_fd(stderr_rx) };
-        handles.insert("stderr".to_owned(), stderr);
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if enabled {
+        let unlimited = libc::rlimit { rlim_cur: libc::RLIM_INFINITY, rlim_max: libc::RLIM_INFINITY };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &unlimited) } == 0 {
+            return Ok(());
+        }
+        limit.rlim_cur = limit.rlim_max;
+    } else {
+        limit.rlim_cur = 0;
+    }
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
 
-        let stdout_fd = dup(slave_fd)?;
-        fcntl(stdout_fd, F_SETFD(FD_CLOEXEC))?;
-        fcntl(slave_fd, F_SETFD(FD_CLOEXEC))?;
-        fcntl(stderr_tx, F_SETFD(FD_CLOEXEC))?;
-        let stdin = unsafe { Stdio::from_raw_fd(slave_fd) };
-        let stdout = unsafe { Stdio::from_raw_fd(stdout_fd) };
-        let stderr = unsafe { Stdio::from_raw_fd(stderr_tx) };
+/// Bring up the loopback interface, which starts out down in a new network
+/// namespace.
+#[cfg(target_os = "linux")]
+fn bring_up_loopback() -> io::Result<()> {
+    use nix::libc;
 
-        let child = cmd.stdin(stdin)
-                       .stdout(stdout)
-                        // Must close the slave FD to not wait indefinitely the end of the proxy
-                       .stderr(stderr)
-                        // Don't check the error of setsid because it fails if we're the
-                        // process leader already. We just forked so it shouldn't return
-                        // error, but ignore it anyway.
-                       .before_exec(|| { nix::unistd::setsid().ok(); Ok(()) })
-                       .spawn()?;
-        Ok(child)
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
     }
+    let result = unsafe {
+        if libc::ioctl(sock, libc::SIOCGIFFLAGS as _, &mut req) == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            if libc::ioctl(sock, libc::SIOCSIFFLAGS as _, &req) == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+    };
+    unsafe { libc::close(sock) };
+    result
+}
 
-    #[cfg(unix)]
-    fn open_session(&self,
-                    cmd: Command,
-                    mut handles: HashMap<String, File>)
-                    -> Result<running::Running, RunnyError> {
-        let pty = openpty(None, None)?;
+/// An environment for the child that it installs itself, rather than having
+/// Command pass one to exec().  This is for socket activation, in the style
+/// of systemd: `fds` moved to 3 onwards, and LISTEN_FDS and LISTEN_PID
+/// describing them.  It's also for secrets, which are only copied into
+/// buffers that are zeroed when this is dropped, which happens once the child
+/// has been started.  LISTEN_PID can only be known after fork(), so
+/// everything is allocated beforehand, and the child only moves fds around,
+/// fills in its pid, and points environ at `envp`.  Command is left with no
+/// environment of its own so that it execs with environ as it is.
+#[cfg(unix)]
+struct ChildEnvironment {
+    fds: Vec<RawFd>,
+    /// Where each of `fds` is moved to on the way, so that none of them is
+    /// overwritten before it has been moved into place.
+    moved: Vec<RawFd>,
+    /// Each variable as "KEY=value", with a trailing NUL.
+    env: Vec<zeroize::Zeroizing<Vec<u8>>>,
+    /// The entry in `env` for LISTEN_PID, whose digits are left as NULs, if
+    /// there are `fds` to describe.
+    pid_index: Option<usize>,
+    envp: Vec<*const nix::libc::c_char>,
+}
 
-        fcntl(pty.master, F_SETFD(FD_CLOEXEC))?;
-        fcntl(pty.slave, F_SETFD(FD_CLOEXEC))?;
+// The pointers in `envp` only point into `env`, which moves with them.
+#[cfg(unix)]
+unsafe impl Send for ChildEnvironment {}
+#[cfg(unix)]
+unsafe impl Sync for ChildEnvironment {}
 
-        // Disable character echo.
-        let mut termios_master = termios::tcgetattr(pty.master)?;
-        termios_master.input_flags &=
-            !(termios::IGNBRK | termios::BRKINT | termios::PARMRK | termios::ISTRIP |
-              termios::INLCR | termios::IGNCR | termios::ICRNL | termios::IXON);
-        termios_master.output_flags &= !termios::OPOST;
-        termios_master.local_flags &=
-            !(termios::ECHO | termios::ECHONL | termios::ICANON | termios::ISIG | termios::IEXTEN);
-        termios_master.control_flags &= !(termios::CSIZE | termios::PARENB);
-        termios_master.control_flags |= termios::CS8;
-        termios_master.control_chars[termios::SpecialCharacterIndices::VMIN as usize] = 1;
-        termios_master.control_chars[termios::SpecialCharacterIndices::VTIME as usize] = 0;
-        termios::tcsetattr(pty.master, termios::SetArg::TCSANOW, &termios_master)?;
+#[cfg(unix)]
+impl ChildEnvironment {
+    const LISTEN_PID: &'static [u8] = b"LISTEN_PID=";
 
-        let child = self.spawn(cmd, pty.slave, &mut handles)?;
+    /// Build the environment the child will have, which is this process's
+    /// with the changes made to `cmd`, and `secrets` on top.
+    fn new(fds: Vec<RawFd>, cmd: &Command, secrets: &[(String, SecretString)]) -> io::Result<ChildEnvironment> {
+        fn var(key: &[u8], value: &[u8]) -> io::Result<zeroize::Zeroizing<Vec<u8>>> {
+            // Sized up front, so that it's never reallocated and leaves
+            // behind a copy that isn't zeroed.
+            let mut var = zeroize::Zeroizing::new(Vec::with_capacity(key.len() + value.len() + 2));
+            var.extend_from_slice(key);
+            var.push(b'=');
+            var.extend_from_slice(value);
+            if var.contains(&0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "environment variable contains a NUL byte"));
+            }
+            var.push(0);
+            Ok(var)
+        }
 
-        let master_dup = dup(pty.master)?;
-        fcntl(master_dup, F_SETFD(FD_CLOEXEC))?;
+        let mut vars: Vec<(OsString, OsString)> = env::vars_os().collect();
+        for (key, value) in cmd.get_envs() {
+            vars.retain(|(k, _)| k != key);
+            if let Some(value) = value {
+                vars.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        vars.retain(|(k, _)| !secrets.iter().any(|(key, _)| k == key.as_str()));
+        if !fds.is_empty() {
+            vars.retain(|(k, _)| k != "LISTEN_FDS" && k != "LISTEN_PID");
+        }
 
-        let stdin = unsafe { File::from_raw_fd(master_dup) };
-        let stdout = unsafe { File::from_raw_fd(pty.master) };
-        Ok(running::Running::new(child, stdin, stdout, self.timeout, handles))
+        let mut env = vec![];
+        for (key, value) in vars {
+            env.push(var(key.as_bytes(), value.as_bytes())?);
+        }
+        for (key, value) in secrets {
+            env.push(var(key.as_bytes(), value.expose_secret().as_bytes())?);
+        }
+        let pid_index = if fds.is_empty() {
+            None
+        } else {
+            env.push(var(b"LISTEN_FDS", fds.len().to_string().as_bytes())?);
+            let mut pid = Self::LISTEN_PID.to_vec();
+            pid.extend_from_slice(&[0; 11]);
+            env.push(zeroize::Zeroizing::new(pid));
+            Some(env.len() - 1)
+        };
+
+        let mut envp: Vec<*const nix::libc::c_char> = env.iter().map(|var| var.as_ptr() as *const _).collect();
+        envp.push(std::ptr::null());
+        Ok(ChildEnvironment {
+            moved: vec![-1; fds.len()],
+            fds,
+            pid_index,
+            env,
+            envp,
+        })
     }
 
-    #[cfg(windows)]
-    fn open_session(&self,
-                    mut cmd: Command,
-                    mut handles: HashMap<String, File>)
-                    -> Result<running::Running, RunnyError> {
-        use std::env;
-        let mut child =
-            cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    /// Set up the child.  This runs between fork() and exec(), and so sticks
+    /// to plain syscalls and the buffers allocated in new().
+    fn install(&mut self) -> io::Result<()> {
+        use nix::libc;
 
-        if self.path.len() > 0 {
-            cmd.env("PATH", env::join_paths(&self.path).unwrap());
+        extern "C" {
+            static mut environ: *const *const libc::c_char;
         }
 
-        // Transmute the Handles into Files.
-        let stdin = unsafe { File::from_raw_handle(child.stdin.take().unwrap().into_raw_handle()) };
-        let stdout =
-            unsafe { File::from_raw_handle(child.stdout.take().unwrap().into_raw_handle()) };
-        let stderr =
-            unsafe { File::from_raw_handle(child.stderr.take().unwrap().into_raw_handle()) };
+        let first = 3 + self.fds.len() as RawFd;
+        for (fd, moved) in self.fds.iter().zip(self.moved.iter_mut()) {
+            *moved = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, first) };
+            if *moved == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (i, &fd) in self.moved.iter().enumerate() {
+            if unsafe { libc::dup2(fd, 3 + i as RawFd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
 
-        handles.insert("stderr".to_string(), stderr);
+        if let Some(index) = self.pid_index {
+            let var = &mut self.env[index][Self::LISTEN_PID.len()..];
+            write_decimal(var, unsafe { libc::getpid() } as u32);
+        }
 
-        Ok(running::Running::new(child, stdin, stdout, self.timeout, handles))
+        unsafe { environ = self.envp.as_ptr() };
+        Ok(())
     }
+}
 
-    pub fn start(&self) -> Result<running::Running, RunnyError> {
-
-        let mut args = Self::make_command(self.cmd.as_str()).unwrap();
-        let cmd = args.remove(0);
-        let handles = HashMap::new();
+/// Wait for the child to send its pid over `socket` from
+/// wait_for_perf_counters(), then open its perf counters, and tell it
+/// whether that worked.  The counters for `events` are returned as handles
+/// named "perf:<index>", and syscall counters as "syscall:<name>".  There are
+/// none if the child never got that far.
+#[cfg(all(target_os = "linux", feature = "perf"))]
+fn open_child_counters(mut socket: UnixStream,
+                       events: Vec<running::PerfEvent>,
+                       count_syscalls: bool)
+                       -> io::Result<Vec<(String, File)>> {
+    use std::io::Read;
 
-        let mut cmd = Command::new(&cmd);
-        cmd.args(args.as_slice());
-        //        cmd.env_clear();
-        if let Some(ref wd) = self.working_directory {
-            cmd.current_dir(wd);
+    fn open_counters(pid: i32,
+                     events: &[running::PerfEvent],
+                     count_syscalls: bool)
+                     -> io::Result<Vec<(String, File)>> {
+        let mut counters = vec![];
+        for (i, counter) in running::open_perf_events(events, pid)?.into_iter().enumerate() {
+            counters.push((format!("perf:{}", i), counter));
         }
+        if count_syscalls {
+            for (name, counter) in running::open_syscall_counters(pid)? {
+                counters.push((format!("syscall:{}", name), counter));
+            }
+        }
+        Ok(counters)
+    }
+
+    let mut pid = [0; 4];
+    if socket.read_exact(&mut pid).is_err() {
+        return Ok(vec![]);
+    }
+    let result = open_counters(i32::from_ne_bytes(pid), &events, count_syscalls);
+    let errno: i32 = match result {
+        Ok(_) => 0,
+        Err(ref e) => e.raw_os_error().unwrap_or(nix::libc::EIO),
+    };
+    socket.write_all(&errno.to_ne_bytes())?;
+    result
+}
 
-        self.open_session(cmd, handles)
+/// Send our pid to open_child_counters() over `socket` and wait for it to
+/// open the perf counters.  This runs between fork() and exec(), and so sticks
+/// to plain syscalls on buffers on the stack.
+#[cfg(all(target_os = "linux", feature = "perf"))]
+fn wait_for_perf_counters(socket: RawFd) -> io::Result<()> {
+    use nix::libc;
+
+    let pid = unsafe { libc::getpid() }.to_ne_bytes();
+    if unsafe { libc::write(socket, pid.as_ptr() as *const libc::c_void, pid.len()) } != pid.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    let mut errno = [0u8; 4];
+    match unsafe { libc::read(socket, errno.as_mut_ptr() as *mut libc::c_void, errno.len()) } {
+        -1 => Err(io::Error::last_os_error()),
+        4 => match i32::from_ne_bytes(errno) {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        },
+        _ => Err(io::Error::from_raw_os_error(libc::EPIPE)),
     }
+}
 
-    fn make_command(cmd: &str) -> Result<Vec<String>, RunnyError> {
-        let cmd = cmd.to_string().replace("\\", "\\\\");
-        let cmd = cmd.as_str();
-        match shlex::split(cmd) {
-            None => Err(RunnyError::NoCommandSpecified),
-            Some(s) => Ok(s),
+/// Write `n` in decimal at the start of `buf`, returning how many digits that
+/// took.  This doesn't allocate, so it's fine to use between fork() and
+/// exec().
+#[cfg(unix)]
+fn write_decimal(buf: &mut [u8], mut n: u32) -> usize {
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        count += 1;
+        n /= 10;
+        if n == 0 {
+            break;
         }
     }
+    for (slot, digit) in buf.iter_mut().zip(digits[..count].iter().rev()) {
+        *slot = *digit;
+    }
+    count
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Read;
-
-    #[cfg(unix)]
-    use std::io::{BufRead, Write};
-    use std::time::Instant;
+/// Whether `pid` has a handler installed for `sig`, going by the SigCgt mask
+/// in /proc/{pid}/status.  This is called from a signal handler, so it sticks
+/// to plain syscalls on buffers on the stack.
+#[cfg(target_os = "linux")]
+fn catches_signal(pid: nix::libc::pid_t, sig: nix::libc::c_int) -> bool {
+    use nix::libc;
 
-    #[cfg(windows)]
-    extern crate winapi;
-    #[cfg(windows)]
-    extern crate user32;
+    let mut path = [0u8; 32];
+    path[..6].copy_from_slice(b"/proc/");
+    let len = 6 + write_decimal(&mut path[6..], pid as u32);
+    path[len..len + 7].copy_from_slice(b"/status");
 
-    #[cfg(unix)]
-    #[test]
-    fn launch_echo() {
-        let mut running = Runny::new("/bin/echo -n 'Launch test echo works'").start().unwrap();
-        let mut simple_str = String::new();
+    let fd = unsafe { libc::open(path.as_ptr() as *const libc::c_char, libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return false;
+    }
+    let mut buf = [0u8; 8192];
+    let mut len = 0;
+    while len < buf.len() {
+        match unsafe { libc::read(fd, buf[len..].as_mut_ptr() as *mut libc::c_void, buf.len() - len) } {
+            n if n > 0 => len += n as usize,
+            _ => break,
+        }
+    }
+    unsafe { libc::close(fd) };
 
-        running.read_to_string(&mut simple_str).unwrap();
-        assert_eq!(simple_str, "Launch test echo works");
+    let field = b"\nSigCgt:\t";
+    let start = match buf[..len].windows(field.len()).position(|w| w == field) {
+        Some(pos) => pos + field.len(),
+        None => return false,
+    };
+    let mut mask = 0u64;
+    for &b in buf[start..len].iter().take_while(|&&b| b != b'\n') {
+        mask = mask << 4 | (b as char).to_digit(16).unwrap_or(0) as u64;
     }
+    mask & 1 << (sig - 1) != 0
+}
 
-    #[cfg(unix)]
-    #[test]
-    fn test_multi_lines() {
-        let mut running = Runny::new("/usr/bin/seq 1 5").start().unwrap();
+/// Enter a new PID namespace and fork, so that the process which goes on to
+/// exec() is PID 1 inside it.  The intermediate process stays behind only to
+/// pass the exit status back to the parent, and to pass on the signals that
+/// terminate() and friends send it.
+#[cfg(target_os = "linux")]
+fn become_container_init() -> io::Result<()> {
+    use nix::libc;
+    use nix::sched::{unshare, CLONE_NEWPID};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use std::mem;
+    use std::sync::atomic::AtomicI32;
 
-        let mut vec = vec![];
-        for line in io::BufReader::new(running.take_output()).lines() {
-            vec.push(line.unwrap());
+    const FORWARDED: [libc::c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+    static WORKLOAD: AtomicI32 = AtomicI32::new(0);
+    /// The signal the workload was killed in place of, if any.
+    static KILLED_BY: AtomicI32 = AtomicI32::new(0);
+
+    // As PID 1, the command only gets signals from outside the namespace
+    // that it has a handler for.  Anything else would be dropped, so stand
+    // in for the default action by killing it.
+    extern "C" fn forward(sig: libc::c_int) {
+        let pid = WORKLOAD.load(Ordering::SeqCst);
+        if catches_signal(pid, sig) {
+            unsafe { libc::kill(pid, sig) };
+        } else if KILLED_BY.compare_exchange(0, sig, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
         }
-        assert_eq!(vec.len(), 5);
-        let vec_parsed: Vec<i32> = vec.iter().map(|x| x.parse().unwrap()).collect();
-        assert_eq!(vec_parsed, vec![1, 2, 3, 4, 5]);
     }
 
-    #[cfg(unix)]
-    #[test]
-    fn terminate_works() {
-        let timeout_secs = 5;
+    // Hold the signals off until the waiter is ready to forward them.  The
+    // command gets the original mask back, and with it any that arrived.
+    let mut forwarded: libc::sigset_t = unsafe { mem::zeroed() };
+    let mut old_mask: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut forwarded);
+        for &sig in &FORWARDED {
+            libc::sigaddset(&mut forwarded, sig);
+        }
+        libc::sigprocmask(libc::SIG_BLOCK, &forwarded, &mut old_mask);
+    }
 
-        let mut running = Runny::new("/bin/bash -c 'sleep 1000'").start().unwrap();
+    unshare(CLONE_NEWPID).map_err(nix_to_io)?;
+    let child = match fork().map_err(nix_to_io)? {
+        ForkResult::Child => {
+            unsafe { libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut()) };
+            if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1 as libc::c_ulong) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // Don't outlive the waiter if it's killed outright.
+            if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL as libc::c_ulong) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        }
+        ForkResult::Parent { child } => child,
+    };
 
-        let mut s = String::new();
-        let start_time = Instant::now();
-        running.terminate(Some(Duration::from_secs(timeout_secs))).unwrap();
-        let end_time = Instant::now();
-        running.read_to_string(&mut s).unwrap();
+    WORKLOAD.store(libc::pid_t::from(child), Ordering::SeqCst);
+    for &sig in &FORWARDED {
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(sig, &action, std::ptr::null_mut());
+        }
+    }
+    unsafe { libc::sigprocmask(libc::SIG_UNBLOCK, &forwarded, std::ptr::null_mut()) };
 
-        assert_eq!(s, "");
-        // Give one extra second for timeout, to account for plumbing.
-        assert!(end_time.duration_since(start_time) < Duration::from_secs(timeout_secs + 1));
+    // Close everything but stdio, otherwise the parent never sees the exec()
+    // of the grandchild close the status pipe.
+    if unsafe { libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) } == -1 {
+        let max_fd = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+        for fd in 3..max_fd as libc::c_int {
+            unsafe { libc::close(fd) };
+        }
     }
 
-    #[cfg(unix)]
-    #[test]
-    fn timeout_works() {
-        let timeout_secs = 3;
-        let start_time = Instant::now();
+    loop {
+        let code = match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => code as u8 as libc::c_int,
+            Ok(WaitStatus::Signaled(_, sig, _)) => {
+                match KILLED_BY.load(Ordering::SeqCst) {
+                    0 => 128 + sig as libc::c_int,
+                    killed_by => 128 + killed_by,
+                }
+            }
+            Err(nix::Error::Sys(nix::Errno::EINTR)) | Ok(_) => continue,
+            Err(_) => 1,
+        };
+        unsafe { libc::_exit(code) };
+    }
+}
 
-        let mut running = Runny::new("/bin/bash -c 'echo -n Hi there; sleep 1000; echo -n Bye \
-                                      there'")
-            .timeout(Duration::from_secs(timeout_secs))
-            .start()
-            .unwrap();
+/// Look up every group `name` is a member of, including its primary group.
+#[cfg(target_os = "linux")]
+fn user_groups(name: &str) -> io::Result<Vec<u32>> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::{mem, ptr};
 
-        let mut s = String::new();
+    let c_name = CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+    // The suggested size is only a starting point, so grow it if the entry
+    // doesn't fit.
+    let mut size = match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+        n if n > 0 => n as usize,
+        _ => 16384,
+    };
+    let mut buf;
+    let ret = loop {
+        buf = vec![0 as libc::c_char; size];
+        match unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found)
+        } {
+            libc::ERANGE => size *= 2,
+            ret => break ret,
+        }
+    };
+    if found.is_null() {
+        return Err(match ret {
+            0 => io::Error::new(io::ErrorKind::NotFound, format!("no such user: {}", name)),
+            e => io::Error::from_raw_os_error(e),
+        });
+    }
+
+    let mut capacity: libc::c_int = 32;
+    loop {
+        let mut groups = vec![0; capacity as usize];
+        let mut count = capacity;
+        if unsafe {
+            libc::getgrouplist(c_name.as_ptr(), pwd.pw_gid, groups.as_mut_ptr(), &mut count)
+        } >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        capacity = if count > capacity { count } else { capacity * 2 };
+    }
+}
+
+impl Runny {
+    pub fn new(cmd: &str) -> Runny {
+        Runny {
+            cmd: cmd.to_string(),
+            argv: None,
+            resolved_program: None,
+            env_expansion: false,
+            arg_env: None,
+            working_directory: None,
+            working_directory_env: None,
+            timeout: None,
+            retries: None,
+            shutdown_delay: None,
+            path: vec![],
+            default_path: false,
+            input_filter: None,
+            stdin_line_ending: running::LineEnding::Passthrough,
+            stdout_line_ending: running::LineEnding::Passthrough,
+            env_secrets: vec![],
+            #[cfg(unix)]
+            process_name: None,
+            #[cfg(unix)]
+            umask: None,
+            #[cfg(unix)]
+            coredump_enabled: None,
+            #[cfg(unix)]
+            forwarded_signals: vec![],
+            #[cfg(unix)]
+            pty_echo: false,
+            #[cfg(unix)]
+            pty_size_from_env: None,
+            #[cfg(unix)]
+            stdio_sockets: false,
+            #[cfg(unix)]
+            stdio_unix_socket: None,
+            #[cfg(unix)]
+            activation_fds: vec![],
+            #[cfg(unix)]
+            stdio_timeout: None,
+            #[cfg(unix)]
+            fd_passing: false,
+            #[cfg(unix)]
+            notify_socket: false,
+            #[cfg(unix)]
+            syslog: None,
+            #[cfg(target_os = "linux")]
+            pipe_limit: None,
+            #[cfg(target_os = "linux")]
+            pipe_flags: nix::fcntl::OFlag::empty(),
+            #[cfg(target_os = "linux")]
+            namespaces: nix::sched::CloneFlags::empty(),
+            #[cfg(target_os = "linux")]
+            loopback: false,
+            #[cfg(all(target_os = "linux", feature = "perf"))]
+            count_syscalls: false,
+            #[cfg(all(target_os = "linux", feature = "perf"))]
+            perf_events: vec![],
+            #[cfg(target_os = "linux")]
+            tmpfs_mounts: vec![],
+            #[cfg(target_os = "linux")]
+            container_init: false,
+            #[cfg(target_os = "linux")]
+            parent_death_signal: None,
+            #[cfg(target_os = "linux")]
+            oom_score_adj: None,
+            #[cfg(target_os = "linux")]
+            sysfs_writes: vec![],
+            #[cfg(target_os = "linux")]
+            scheduler: None,
+            #[cfg(target_os = "linux")]
+            supplementary_groups: None,
+        }
+    }
+
+    /// Create a Runny from an argument vector that has already been split,
+    /// bypassing shell-style parsing entirely.
+    pub fn from_argv<S: AsRef<OsStr>>(argv: &[S]) -> Result<Runny, RunnyError> {
+        if argv.is_empty() {
+            return Err(RunnyError::NoCommandSpecified);
+        }
+        let argv: Vec<OsString> = argv.iter().map(|a| a.as_ref().to_os_string()).collect();
+        let cmd = argv.iter()
+            .map(|a| shlex::quote(&a.to_string_lossy()).into_owned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut runny = Runny::new(&cmd);
+        runny.argv = Some(argv);
+        Ok(runny)
+    }
+
+    pub fn directory(&mut self, wd: &Option<PathBuf>) -> &mut Runny {
+        self.working_directory = wd.clone();
+        self
+    }
+
+    /// Use the directory named by the environment variable `var` at the time
+    /// start() is called, falling back to directory() if it isn't set.
+    pub fn with_working_directory_from_env(&mut self, var: &str) -> &mut Runny {
+        self.working_directory_env = Some(var.to_owned());
+        self
+    }
+
+    pub fn path(&mut self, path: Vec<PathBuf>) -> &mut Runny {
+        self.path = path;
+        self
+    }
+
+    /// Set the child's PATH to a minimal default for the platform, such as
+    /// `/usr/local/bin:/usr/bin:/bin`, rather than inheriting ours.  Any
+    /// directories given to path() are searched before the default ones.
+    pub fn with_default_path(&mut self) -> &mut Runny {
+        self.default_path = true;
+        self
+    }
+
+    #[cfg(unix)]
+    fn default_path() -> Vec<PathBuf> {
+        vec![PathBuf::from("/usr/local/bin"), PathBuf::from("/usr/bin"), PathBuf::from("/bin")]
+    }
+
+    #[cfg(windows)]
+    fn default_path() -> Vec<PathBuf> {
+        let root = env::var_os("SystemRoot").unwrap_or_else(|| OsString::from("C:\\Windows"));
+        vec![PathBuf::from(root).join("System32")]
+    }
+
+    /// The directories to search for the program, or None to use our PATH.
+    fn search_path(&self) -> Option<Vec<PathBuf>> {
+        if self.default_path {
+            let mut dirs = self.path.clone();
+            dirs.extend(Self::default_path());
+            Some(dirs)
+        } else if !self.path.is_empty() {
+            Some(self.path.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Runny {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run the command again, up to `n` more times and `delay` apart, for as
+    /// long as it exits with a non-zero code.  start() then waits for the
+    /// command to exit, and returns the first attempt that succeeded or else
+    /// the last one, with its output held in memory so that it can still be
+    /// read.  Each attempt gets a timeout of its own.  Failing to start the
+    /// command at all isn't retried.
+    pub fn retries(&mut self, n: u32, delay: Duration) -> &mut Runny {
+        self.retries = Some((n, delay));
+        self
+    }
+
+    /// How long to wait between SIGTERM and SIGKILL when terminate() is
+    /// called without a timeout of its own, or when the timeout expires.
+    pub fn with_graceful_shutdown_delay(&mut self, delay: Duration) -> &mut Runny {
+        self.shutdown_delay = Some(delay);
+        self
+    }
+
+    /// The command line this Runny was created with.
+    pub fn command(&self) -> &str {
+        &self.cmd
+    }
+
+    /// The working directory set by directory(), if any.  This doesn't
+    /// account for with_working_directory_from_env().
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.working_directory.as_deref()
+    }
+
+    /// The timeout set by timeout(), if any.  This has a get_ prefix since
+    /// timeout() is already the setter.
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The search path set by path(), which is likewise taken by the setter.
+    pub fn get_path(&self) -> &[PathBuf] {
+        &self.path
+    }
+
+    /// Pass everything written to the child's input through `f` first.
+    pub fn input_filter<F>(&mut self, f: F) -> &mut Runny
+        where F: Fn(&[u8]) -> Vec<u8> + Send + 'static
+    {
+        self.input_filter = Some(Arc::new(Mutex::new(Box::new(f))));
+        self
+    }
+
+    /// Translate line endings in everything written to the child's input,
+    /// after any input_filter().  A "\r" at the end of a write may be held
+    /// back until the next one, to see whether it's part of "\r\n".
+    pub fn with_stdin_line_ending(&mut self, mode: running::LineEnding) -> &mut Runny {
+        self.stdin_line_ending = mode;
+        self
+    }
+
+    /// Translate line endings in everything read from the child's stdout.
+    pub fn with_stdout_line_ending(&mut self, mode: running::LineEnding) -> &mut Runny {
+        self.stdout_line_ending = mode;
+        self
+    }
+
+    /// Pass `name` to the child as argv[0], which is what tools like `ps`
+    /// show.  This doesn't change which program is run.
+    #[cfg(unix)]
+    pub fn with_process_name(&mut self, name: &str) -> &mut Runny {
+        self.process_name = Some(name.into());
+        self
+    }
+
+    /// Set the child's file creation mask.  start() fails if `mask` doesn't
+    /// fit in 0o7777.
+    #[cfg(unix)]
+    pub fn with_umask(&mut self, mask: u32) -> &mut Runny {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Turn core dumps on or off for the child by setting its RLIMIT_CORE to
+    /// unlimited or 0, rather than leaving it inherited.  If the hard limit
+    /// can't be raised, enabling them raises the soft limit as far as it can
+    /// go instead.
+    #[cfg(unix)]
+    pub fn with_coredump_enabled(&mut self, enabled: bool) -> &mut Runny {
+        self.coredump_enabled = Some(enabled);
+        self
+    }
+
+    /// Pass each of `signals` on to the child whenever this process receives
+    /// it, such as SIGINT from Ctrl-C, which would otherwise not reach a child
+    /// in its own session.  This replaces this process's handlers for those
+    /// signals until every Running forwarding them has been dropped.
+    /// SIGKILL and SIGSTOP can't be forwarded, and make start() fail.
+    #[cfg(unix)]
+    pub fn forward_signals(&mut self, signals: &[nix::sys::signal::Signal]) -> &mut Runny {
+        self.forwarded_signals.extend_from_slice(signals);
+        self
+    }
+
+    /// Have the pty echo input back to the output, as a normal terminal would.
+    /// Echo is disabled by default.
+    #[cfg(unix)]
+    pub fn with_pty_echo(&mut self, enabled: bool) -> &mut Runny {
+        self.pty_echo = enabled;
+        self
+    }
+
+    /// Size the pty from $COLUMNS and $LINES, as they are when the command is
+    /// started, using `default_cols` or `default_rows` for either one that is
+    /// missing or isn't a number.
+    #[cfg(unix)]
+    pub fn with_pty_size_from_env(&mut self, default_cols: u16, default_rows: u16) -> &mut Runny {
+        self.pty_size_from_env = Some((default_cols, default_rows));
+        self
+    }
+
+    /// Connect the child's stdin and stdout to one end of a Unix socket pair
+    /// rather than a pty.  The other end is available from Running::take_io().
+    #[cfg(unix)]
+    pub fn with_stdio_sockets(&mut self) -> &mut Runny {
+        self.stdio_sockets = true;
+        self
+    }
+
+    /// Connect the child's stdin, stdout and stderr to the Unix socket
+    /// listening at `path`, as some process supervisors expect.  The socket is
+    /// connected to when the child is started.  The Running's own stdin and
+    /// stdout then go nowhere, and Running::has_socket_stdio() is true.
+    #[cfg(unix)]
+    pub fn with_stdio_from_unix_socket<P: AsRef<Path>>(&mut self, path: P) -> &mut Runny {
+        self.stdio_unix_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Pass listening sockets to the child the way systemd's socket activation
+    /// does, as fds 3 onwards with LISTEN_FDS and LISTEN_PID set.  The fds
+    /// are still owned by the caller, and must be kept open until the child
+    /// has started.
+    #[cfg(unix)]
+    pub fn with_socket_activation(&mut self, fds: Vec<RawFd>) -> &mut Runny {
+        self.activation_fds = fds;
+        self
+    }
+
+    /// Have reads from stdout and stderr, and writes to stdin, fail with
+    /// WouldBlock rather than blocking for longer than the given timeouts.
+    /// With with_stdio_sockets() this sets SO_RCVTIMEO and SO_SNDTIMEO on
+    /// the socket, otherwise the pty is made non-blocking and polled.
+    #[cfg(unix)]
+    pub fn with_stdio_timeout(&mut self, read: Duration, write: Duration) -> &mut Runny {
+        self.stdio_timeout = Some((read, write));
+        self
+    }
+
+    /// Resize the stderr pipe to hold `bytes`, after which the child blocks
+    /// until it is read.  The kernel rounds this up to a whole page.
+    /// stdin and stdout go through the pty, and so are unaffected.
+    #[cfg(target_os = "linux")]
+    pub fn with_pipe_limit(&mut self, bytes: usize) -> &mut Runny {
+        self.pipe_limit = Some(bytes);
+        self
+    }
+
+    /// Create the stderr pipe with `flags`.  The only flag accepted is
+    /// O_DIRECT, which puts the pipe into packet mode, where each write() is
+    /// read back by a separate read(), up to PIPE_BUF bytes at a time, and so
+    /// it doesn't mix with reading by lines.  The flags apply to both ends of
+    /// the pipe, including the child's stderr, so anything else, such as
+    /// O_NONBLOCK, makes start() fail with EINVAL.  stdin and stdout go
+    /// through the pty, and so are unaffected.
+    #[cfg(target_os = "linux")]
+    pub fn with_pipe_flags(&mut self, flags: nix::fcntl::OFlag) -> &mut Runny {
+        self.pipe_flags = flags;
+        self
+    }
+
+    /// Connect the child to a Unix datagram socket that can be used to pass
+    /// file descriptors to it after it has started.  The child's end is
+    /// named by the RUNNY_FD_SOCKET environment variable, and the parent's
+    /// end is available from Running::fd_passing_socket().
+    #[cfg(unix)]
+    pub fn with_fd_passing(&mut self) -> &mut Runny {
+        self.fd_passing = true;
+        self
+    }
+
+    /// Give the child a $NOTIFY_SOCKET to report readiness on, in the style of
+    /// systemd's sd_notify().  Use Running::wait_for_notify() to wait for it.
+    #[cfg(unix)]
+    pub fn with_notify_socket(&mut self) -> &mut Runny {
+        self.notify_socket = true;
+        self
+    }
+
+    /// Send the child's stderr to syslog through `logger`, tagged with `ident`
+    /// and logged at the info level of `facility` (e.g. `libc::LOG_DAEMON`).
+    /// Nothing will be readable from Running::take_error().
+    #[cfg(unix)]
+    pub fn with_syslog<S: Into<String>>(&mut self, ident: S, facility: nix::libc::c_int) -> &mut Runny {
+        self.syslog = Some((ident.into(), facility));
+        self
+    }
+
+    #[cfg(unix)]
+    fn start_logger(ident: &str, facility: nix::libc::c_int) -> Result<Child, RunnyError> {
+        use nix::libc::*;
+        let facility = match facility {
+            LOG_KERN => "kern",
+            LOG_USER => "user",
+            LOG_MAIL => "mail",
+            LOG_DAEMON => "daemon",
+            LOG_AUTH => "auth",
+            LOG_SYSLOG => "syslog",
+            LOG_LPR => "lpr",
+            LOG_NEWS => "news",
+            LOG_UUCP => "uucp",
+            LOG_CRON => "cron",
+            LOG_AUTHPRIV => "authpriv",
+            LOG_FTP => "ftp",
+            LOG_LOCAL0 => "local0",
+            LOG_LOCAL1 => "local1",
+            LOG_LOCAL2 => "local2",
+            LOG_LOCAL3 => "local3",
+            LOG_LOCAL4 => "local4",
+            LOG_LOCAL5 => "local5",
+            LOG_LOCAL6 => "local6",
+            LOG_LOCAL7 => "local7",
+            _ => return Err(RunnyError::RunnyIoError(io::Error::from_raw_os_error(EINVAL))),
+        };
+        Ok(Command::new("logger")
+            .arg("-t")
+            .arg(ident)
+            .arg("-p")
+            .arg(format!("{}.info", facility))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?)
+    }
+
+    /// Give the child its own IPC namespace, so that message queues and shared
+    /// memory it creates aren't visible outside it.  This requires CAP_SYS_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn with_ipc_namespace(&mut self) -> &mut Runny {
+        self.namespaces |= nix::sched::CLONE_NEWIPC;
+        self
+    }
+
+    /// Give the child its own UTS namespace, so that it may change its hostname
+    /// without affecting the host.  This requires CAP_SYS_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn with_uts_namespace(&mut self) -> &mut Runny {
+        self.namespaces |= nix::sched::CLONE_NEWUTS;
+        self
+    }
+
+    /// Cut the child off from the network by giving it its own network
+    /// namespace, in which the only interface is loopback.  If `loopback` is
+    /// set then it is brought up, so that the child may still talk to itself
+    /// over 127.0.0.1, otherwise it's left down.  This requires CAP_SYS_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn with_network_disabled(&mut self, loopback: bool) -> &mut Runny {
+        self.namespaces |= nix::sched::CLONE_NEWNET;
+        self.loopback = loopback;
+        self
+    }
+
+    /// Count each syscall the child makes, for Running::syscall_count().  The
+    /// counters are perf tracepoint events, so tracefs must be mounted and
+    /// perf_event_paranoid must allow it, which usually means CAP_PERFMON.
+    /// The counters are opened on the child as it waits just before exec(),
+    /// and start counting from exec().
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn count_syscalls(&mut self) -> &mut Runny {
+        self.count_syscalls = true;
+        self
+    }
+
+    /// Count `events` in the child, for Running::perf_stats().  The counters
+    /// are opened on the child as it waits just before exec(), so that they
+    /// count only the new program, and starting fails if any can't be opened.
+    /// Hardware counters usually aren't available inside virtual machines.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn with_perf_events(&mut self, events: Vec<running::PerfEvent>) -> &mut Runny {
+        self.perf_events = events;
+        self
+    }
+
+    /// Mount a fresh tmpfs of `size_mb` megabytes over `path` in a new mount
+    /// namespace, which goes away along with the child.  `path` must already
+    /// exist.  This requires CAP_SYS_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn with_tmpfs_at(&mut self, path: &str, size_mb: u64) -> &mut Runny {
+        self.tmpfs_mounts.push((PathBuf::from(path), format!("size={}M", size_mb)));
+        self
+    }
+
+    /// Run the command as PID 1 of a new PID namespace, and make it a child
+    /// subreaper so that orphaned descendants are reparented to it.
+    /// The exit code of a command killed by a signal is reported as 128 + signal.
+    /// A waiting process sits between this one and the command; Running::pid()
+    /// and the /proc readers refer to the command itself.  The waiter passes
+    /// on SIGTERM, SIGINT, and SIGHUP, and kills the command in their place
+    /// if it has no handler for them, since as PID 1 it would otherwise
+    /// ignore them.  This requires CAP_SYS_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn with_container_init(&mut self) -> &mut Runny {
+        self.container_init = true;
+        self
+    }
+
+    /// Have the kernel send `sig` to the child when its parent goes away.
+    /// Note that Linux tracks the thread that called start(), not the whole
+    /// process, so the signal also fires if that thread exits.
+    #[cfg(target_os = "linux")]
+    pub fn terminate_on_parent_exit(&mut self, sig: nix::sys::signal::Signal) -> &mut Runny {
+        self.parent_death_signal = Some(sig);
+        self
+    }
+
+    /// Set the child's OOM killer adjustment, clamped to the range -1000
+    /// (never kill) to 1000 (kill first).  Lowering it below the parent's
+    /// value requires CAP_SYS_RESOURCE.
+    #[cfg(target_os = "linux")]
+    pub fn with_oom_score_adj(&mut self, score: i16) -> &mut Runny {
+        self.oom_score_adj = Some(score.clamp(-1000, 1000));
+        self
+    }
+
+    /// Make the child the first process the OOM killer picks.
+    #[cfg(target_os = "linux")]
+    pub fn with_oom_score_adj_max(&mut self) -> &mut Runny {
+        self.with_oom_score_adj(1000)
+    }
+
+    /// Write `value` to the sysfs file at `path` in the child just before
+    /// exec(), such as to set a control knob it depends on.  Writes are made
+    /// in the order they were added, and start() fails if any of them does.
+    #[cfg(target_os = "linux")]
+    pub fn with_sysfs_write<P: Into<String>, V: Into<String>>(&mut self, path: P, value: V) -> &mut Runny {
+        self.sysfs_writes.push((path.into(), value.into()));
+        self
+    }
+
+    /// Run the child under the given scheduling policy.  `priority` must be 0
+    /// for Normal, Batch, and Idle, and between 1 and 99 for Fifo and
+    /// RoundRobin, which also require CAP_SYS_NICE.  If the policy can't be
+    /// applied, start() fails.
+    #[cfg(target_os = "linux")]
+    pub fn with_scheduler(&mut self, policy: SchedPolicy, priority: u32) -> &mut Runny {
+        self.scheduler = Some((policy, priority));
+        self
+    }
+
+    /// Spawn a new process connected to the slave TTY
+    #[cfg(unix)]
+    fn spawn(&self,
+             mut cmd: Command,
+             slave_fd: i32,
+             handles: &mut HashMap<String, File>)
+             -> Result<Child, RunnyError> {
+
+        // When reading from the pty, sometimes we get -EIO or -EBADF,
+        // which can be ignored.  But Rust really doesn't like this.
+        // So send the pty through a pipe, and ignore those errors.
+        //
+        #[cfg(target_os = "linux")]
+        let (stderr_rx, stderr_tx) = pipe2(O_CLOEXEC | self.pipe_flags)?;
+        #[cfg(not(target_os = "linux"))]
+        let (stderr_rx, stderr_tx) = pipe2(O_CLOEXEC)?;
+        fcntl(stderr_rx, F_SETFD(FD_CLOEXEC))?;
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(bytes) = self.pipe_limit {
+                fcntl(stderr_rx, nix::fcntl::FcntlArg::F_SETPIPE_SZ(bytes as nix::libc::c_int))?;
+            }
+        }
+
+        let stderr = unsafe { File::from_raw_fd(stderr_rx) };
+        handles.insert("stderr".to_owned(), stderr);
+
+        let stdout_fd = dup(slave_fd)?;
+        fcntl(stdout_fd, F_SETFD(FD_CLOEXEC))?;
+        fcntl(slave_fd, F_SETFD(FD_CLOEXEC))?;
+        fcntl(stderr_tx, F_SETFD(FD_CLOEXEC))?;
+        let stdin = unsafe { Stdio::from_raw_fd(slave_fd) };
+        let stdout = unsafe { Stdio::from_raw_fd(stdout_fd) };
+        // When logging to syslog, the stderr pipe is left with no writer, and
+        // so reads from it see an immediate EOF.
+        // The same goes for when stderr is sent to a socket along with stdout.
+        let stderr = match handles.remove("syslog") {
+            Some(log) => {
+                nix::unistd::close(stderr_tx)?;
+                Stdio::from(log)
+            }
+            None if self.stdio_unix_socket.is_some() => {
+                nix::unistd::close(stderr_tx)?;
+                let stderr_fd = dup(slave_fd)?;
+                fcntl(stderr_fd, F_SETFD(FD_CLOEXEC))?;
+                unsafe { Stdio::from_raw_fd(stderr_fd) }
+            }
+            None => unsafe { Stdio::from_raw_fd(stderr_tx) },
+        };
+
+        // The child's end of the socket must stay open across exec(), but the
+        // parent's copy of it is closed once this function returns.
+        let fd_socket = if self.fd_passing {
+            let (parent_end, child_end) = UnixDatagram::pair()?;
+            cmd.env("RUNNY_FD_SOCKET", child_end.as_raw_fd().to_string());
+            handles.insert("fd_socket".to_owned(),
+                           unsafe { File::from_raw_fd(parent_end.into_raw_fd()) });
+            Some(child_end)
+        } else {
+            None
+        };
+        let fd_socket_fd = fd_socket.as_ref().map(|s| s.as_raw_fd());
+        let umask = self.umask;
+        let coredump_enabled = self.coredump_enabled;
+
+        // Perf counters are opened from here, on the child as it waits just
+        // before exec(), so that they can start counting from exec().  Only
+        // the pid and a result go over the socket.
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        let (counter_socket, counter_thread) = if self.count_syscalls || !self.perf_events.is_empty() {
+            let (parent_end, child_end) = UnixStream::pair()?;
+            let events = self.perf_events.clone();
+            let count_syscalls = self.count_syscalls;
+            (Some(child_end),
+             Some(thread::spawn(move || open_child_counters(parent_end, events, count_syscalls))))
+        } else {
+            (None, None)
+        };
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        let counter_fd = counter_socket.as_ref().map(|s| s.as_raw_fd());
+
+        // With socket activation or secrets, the child sets up its own
+        // environment, so start again from a Command that leaves it alone.
+        let (mut cmd, mut environment) = if self.activation_fds.is_empty() && self.env_secrets.is_empty() {
+            (cmd, None)
+        } else {
+            let environment = ChildEnvironment::new(self.activation_fds.clone(), &cmd, &self.env_secrets)?;
+            let mut plain = Command::new(cmd.get_program());
+            plain.args(cmd.get_args());
+            if let Some(dir) = cmd.get_current_dir() {
+                plain.current_dir(dir);
+            }
+            if let Some(ref name) = self.process_name {
+                plain.arg0(name);
+            }
+            (plain, Some(environment))
+        };
+
+        #[cfg(target_os = "linux")]
+        let namespaces = self.namespaces;
+        #[cfg(target_os = "linux")]
+        let loopback = self.loopback;
+        #[cfg(target_os = "linux")]
+        let tmpfs_mounts = self.tmpfs_mounts.clone();
+        #[cfg(target_os = "linux")]
+        let container_init = self.container_init;
+        #[cfg(target_os = "linux")]
+        let parent_death_signal = self.parent_death_signal;
+        #[cfg(target_os = "linux")]
+        let oom_score_adj = self.oom_score_adj.map(|score| score.to_string());
+        #[cfg(target_os = "linux")]
+        let sysfs_writes = self.sysfs_writes.clone();
+        #[cfg(target_os = "linux")]
+        let scheduler = self.scheduler;
+        #[cfg(target_os = "linux")]
+        let supplementary_groups = self.supplementary_groups.clone();
+
+        let child = cmd.stdin(stdin)
+                       .stdout(stdout)
+                        // Must close the slave FD to not wait indefinitely the end of the proxy
+                       .stderr(stderr)
+                        // Don't check the error of setsid because it fails if we're the
+                        // process leader already. We just forked so it shouldn't return
+                        // error, but ignore it anyway.
+                       .before_exec(move || {
+                           nix::unistd::setsid().ok();
+
+                           if let Some(fd) = fd_socket_fd {
+                               fcntl(fd, F_SETFD(FdFlag::empty())).map_err(nix_to_io)?;
+                           }
+                           if let Some(mask) = umask {
+                               let mode = nix::sys::stat::Mode::from_bits_truncate(mask as nix::libc::mode_t);
+                               nix::sys::stat::umask(mode);
+                           }
+                           if let Some(enabled) = coredump_enabled {
+                               set_core_limit(enabled)?;
+                           }
+                           if let Some(ref mut environment) = environment {
+                               environment.install()?;
+                           }
+
+                           #[cfg(target_os = "linux")]
+                           {
+                               // This has to come before anything that forks,
+                               // so that the counters follow into the new
+                               // process.
+                               #[cfg(feature = "perf")]
+                               {
+                                   if let Some(fd) = counter_fd {
+                                       wait_for_perf_counters(fd)?;
+                                   }
+                               }
+                               if !namespaces.is_empty() {
+                                   nix::sched::unshare(namespaces).map_err(nix_to_io)?;
+                               }
+                               if loopback {
+                                   bring_up_loopback()?;
+                               }
+                               if !tmpfs_mounts.is_empty() {
+                                   mount_tmpfs(&tmpfs_mounts)?;
+                               }
+                               if container_init {
+                                   become_container_init()?;
+                               }
+                               if let Some(sig) = parent_death_signal {
+                                   let sig = sig as nix::libc::c_ulong;
+                                   if unsafe { nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, sig) } == -1 {
+                                       return Err(io::Error::last_os_error());
+                                   }
+                               }
+                               if let Some(ref score) = oom_score_adj {
+                                   std::fs::write("/proc/self/oom_score_adj", score)?;
+                               }
+                               for (path, value) in &sysfs_writes {
+                                   std::fs::write(path, value)?;
+                               }
+                               if let Some((policy, priority)) = scheduler {
+                                   let param = nix::libc::sched_param {
+                                       sched_priority: priority as nix::libc::c_int,
+                                   };
+                                   if unsafe { nix::libc::sched_setscheduler(0, policy.as_raw(), &param) } == -1 {
+                                       return Err(io::Error::last_os_error());
+                                   }
+                               }
+                               if let Some(ref gids) = supplementary_groups {
+                                   if unsafe { nix::libc::setgroups(gids.len(), gids.as_ptr()) } == -1 {
+                                       return Err(io::Error::last_os_error());
+                                   }
+                               }
+                           }
+                           Ok(())
+                       })
+                       .spawn();
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        {
+            // Closing the child's end lets the thread finish if the child
+            // never got as far as asking for its counters.
+            drop(counter_socket);
+            if let Some(thread) = counter_thread {
+                let counters = thread.join().map_err(|_| io::Error::other("counter thread panicked"))?;
+                if child.is_ok() {
+                    handles.extend(counters?);
+                }
+            }
+        }
+        Ok(child?)
+    }
+
+    /// Start the child, connected to a pty unless sockets were asked for or
+    /// `closable_stdin` is set, in which case shutting down the socket lets
+    /// the child see EOF on stdin.
+    #[cfg(unix)]
+    fn open_session(&self,
+                    cmd: Command,
+                    mut handles: HashMap<String, File>,
+                    closable_stdin: bool)
+                    -> Result<running::Running, RunnyError> {
+        if let Some(ref path) = self.stdio_unix_socket {
+            let socket = UnixStream::connect(path)?;
+            fcntl(socket.as_raw_fd(), F_SETFD(FD_CLOEXEC))?;
+            let child = self.spawn(cmd, socket.into_raw_fd(), &mut handles)?;
+
+            let stdin = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+            let stdout = File::open("/dev/null")?;
+            let mut running = running::Running::new(child, stdin, stdout, self.timeout, handles);
+            running.set_socket_stdio();
+            return Ok(running);
+        }
+
+        if self.stdio_sockets || closable_stdin {
+            let (parent_end, child_end) = UnixStream::pair()?;
+            fcntl(parent_end.as_raw_fd(), F_SETFD(FD_CLOEXEC))?;
+            let child = self.spawn(cmd, child_end.into_raw_fd(), &mut handles)?;
+
+            let stdin_fd = dup(parent_end.as_raw_fd())?;
+            fcntl(stdin_fd, F_SETFD(FD_CLOEXEC))?;
+            let stdout_fd = dup(parent_end.as_raw_fd())?;
+            fcntl(stdout_fd, F_SETFD(FD_CLOEXEC))?;
+
+            let stdin = unsafe { File::from_raw_fd(stdin_fd) };
+            let stdout = unsafe { File::from_raw_fd(stdout_fd) };
+            handles.insert("stdio_socket".to_owned(),
+                           unsafe { File::from_raw_fd(parent_end.into_raw_fd()) });
+            return Ok(running::Running::new(child, stdin, stdout, self.timeout, handles));
+        }
+
+        let winsize = self.pty_size_from_env.map(|(default_cols, default_rows)| {
+            fn from_env(var: &str, default: u16) -> u16 {
+                env_var(var)
+                    .and_then(|v| v.into_string().ok())
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(default)
+            }
+            nix::pty::Winsize {
+                ws_row: from_env("LINES", default_rows),
+                ws_col: from_env("COLUMNS", default_cols),
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            }
+        });
+        let pty = openpty(winsize.as_ref(), None)?;
+
+        fcntl(pty.master, F_SETFD(FD_CLOEXEC))?;
+        fcntl(pty.slave, F_SETFD(FD_CLOEXEC))?;
+
+        // Put the pty into raw mode, and disable character echo unless asked for.
+        let mut termios_master = termios::tcgetattr(pty.master)?;
+        termios_master.input_flags &=
+            !(termios::IGNBRK | termios::BRKINT | termios::PARMRK | termios::ISTRIP |
+              termios::INLCR | termios::IGNCR | termios::ICRNL | termios::IXON);
+        termios_master.output_flags &= !termios::OPOST;
+        termios_master.local_flags &=
+            !(termios::ECHO | termios::ECHONL | termios::ICANON | termios::ISIG | termios::IEXTEN);
+        termios_master.control_flags &= !(termios::CSIZE | termios::PARENB);
+        termios_master.control_flags |= termios::CS8;
+        termios_master.control_chars[termios::SpecialCharacterIndices::VMIN as usize] = 1;
+        termios_master.control_chars[termios::SpecialCharacterIndices::VTIME as usize] = 0;
+        if self.pty_echo {
+            // Echo bytes verbatim, rather than control characters as "^X".
+            termios_master.local_flags |= termios::ECHO;
+            termios_master.local_flags &= !termios::ECHOCTL;
+        }
+        termios::tcsetattr(pty.master, termios::SetArg::TCSANOW, &termios_master)?;
+
+        let child = self.spawn(cmd, pty.slave, &mut handles)?;
+
+        let master_dup = dup(pty.master)?;
+        fcntl(master_dup, F_SETFD(FD_CLOEXEC))?;
+
+        let stdin = unsafe { File::from_raw_fd(master_dup) };
+        let stdout = unsafe { File::from_raw_fd(pty.master) };
+        let mut running = running::Running::new(child, stdin, stdout, self.timeout, handles);
+        running.set_pty();
+        Ok(running)
+    }
+
+    /// Start the child, connected to pipes, which may always be closed.
+    #[cfg(windows)]
+    fn open_session(&self,
+                    mut cmd: Command,
+                    mut handles: HashMap<String, File>,
+                    _closable_stdin: bool)
+                    -> Result<running::Running, RunnyError> {
+        use std::env;
+        let mut child =
+            cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+        if self.path.len() > 0 {
+            cmd.env("PATH", env::join_paths(&self.path).unwrap());
+        }
+
+        // Transmute the Handles into Files.
+        let stdin = unsafe { File::from_raw_handle(child.stdin.take().unwrap().into_raw_handle()) };
+        let stdout =
+            unsafe { File::from_raw_handle(child.stdout.take().unwrap().into_raw_handle()) };
+        let stderr =
+            unsafe { File::from_raw_handle(child.stderr.take().unwrap().into_raw_handle()) };
+
+        handles.insert("stderr".to_string(), stderr);
+
+        Ok(running::Running::new(child, stdin, stdout, self.timeout, handles))
+    }
+
+    pub fn start(&self) -> Result<running::Running, RunnyError> {
+        let (retries, delay) = match self.retries {
+            Some(r) => r,
+            None => return self.start_session(false),
+        };
+
+        let mut exit_codes = vec![];
+        loop {
+            let mut running = self.start_session(false)?;
+            let exit_code = running.buffer_until_exit()?;
+            exit_codes.push(exit_code);
+            if exit_code == 0 || exit_codes.len() > retries as usize {
+                running.set_attempt_exit_codes(exit_codes);
+                return Ok(running);
+            }
+            thread::sleep(delay);
+        }
+    }
+
+    fn start_session(&self, closable_stdin: bool) -> Result<running::Running, RunnyError> {
+        #[cfg(unix)]
+        {
+            if let Some(mask) = self.umask {
+                if mask > 0o7777 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("umask {:o} is out of range", mask))
+                        .into());
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if !(self.pipe_flags - nix::fcntl::O_DIRECT).is_empty() {
+                return Err(io::Error::from_raw_os_error(nix::libc::EINVAL).into());
+            }
+        }
+
+        let mut args = self.args()?;
+        let command = args.iter().map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(" ");
+        let cmd = match self.resolved_program {
+            Some(ref program) => {
+                args.remove(0);
+                program.clone().into_os_string()
+            }
+            None => args.remove(0),
+        };
+        let mut handles = HashMap::new();
+
+        #[cfg(unix)]
+        let mut logger = match self.syslog {
+            Some((ref ident, facility)) => {
+                let mut logger = Self::start_logger(ident, facility)?;
+                let log = logger.stdin.take().unwrap();
+                handles.insert("syslog".to_owned(), unsafe { File::from_raw_fd(log.into_raw_fd()) });
+                Some(logger)
+            }
+            None => None,
+        };
+
+        let mut cmd = Command::new(&cmd);
+        cmd.args(args.as_slice());
+        #[cfg(unix)]
+        {
+            if let Some(ref name) = self.process_name {
+                cmd.arg0(name);
+            }
+        }
+        //        cmd.env_clear();
+        if self.default_path {
+            let path = env::join_paths(self.search_path().unwrap())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            cmd.env("PATH", path);
+        }
+        // On unix, secrets go into an environment that the child installs
+        // itself, so that Command doesn't keep copies of them.
+        #[cfg(not(unix))]
+        for (key, value) in &self.env_secrets {
+            cmd.env(key, value.expose_secret());
+        }
+        let env_wd = self.working_directory_env.as_ref().and_then(|var| env_var(var)).map(PathBuf::from);
+        if let Some(wd) = env_wd.as_ref().or(self.working_directory.as_ref()) {
+            cmd.current_dir(wd);
+        }
+
+        #[cfg(unix)]
+        let notify_socket = if self.notify_socket {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = env::temp_dir().join(format!("runny-notify-{}-{}",
+                                                    std::process::id(),
+                                                    COUNTER.fetch_add(1, Ordering::SeqCst)));
+            let socket = UnixDatagram::bind(&path)?;
+            cmd.env("NOTIFY_SOCKET", &path);
+            Some((socket, path))
+        } else {
+            None
+        };
+
+        let mut running = match self.open_session(cmd, handles, closable_stdin) {
+            Ok(r) => r,
+            Err(e) => {
+                #[cfg(unix)]
+                {
+                    if let Some((_, ref path)) = notify_socket {
+                        std::fs::remove_file(path).ok();
+                    }
+                    if let Some(ref mut logger) = logger {
+                        logger.kill().ok();
+                        logger.wait().ok();
+                    }
+                }
+                return Err(e);
+            }
+        };
+        #[cfg(target_os = "linux")]
+        {
+            if self.container_init {
+                running.set_container_init();
+            }
+        }
+        if let Some(delay) = self.shutdown_delay {
+            running.set_graceful_shutdown_delay(delay);
+        }
+        #[cfg(unix)]
+        {
+            if let Some((socket, path)) = notify_socket {
+                running.set_notify_socket(socket, path);
+            }
+            if let Some(logger) = logger {
+                running.set_log_process(logger);
+            }
+            if let Some((read, write)) = self.stdio_timeout {
+                running.set_stdio_timeout(read, write)?;
+            }
+            if !self.forwarded_signals.is_empty() {
+                running.forward_signals(&self.forwarded_signals)?;
+            }
+        }
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        {
+            if !self.perf_events.is_empty() {
+                running.set_perf_events(&self.perf_events)?;
+            }
+        }
+        if let Some(ref filter) = self.input_filter {
+            running.set_input_filter(filter.clone());
+        }
+        running.set_line_endings(self.stdin_line_ending, self.stdout_line_ending);
+        running.set_command(command);
+        Ok(running)
+    }
+
+    /// Run the command, writing `input` to its stdin and then closing it, and
+    /// return everything it wrote to stdout and stderr along with its exit
+    /// code.  On Unix stdin and stdout go through a socket rather than a pty,
+    /// so that the child sees EOF once the input runs out.
+    pub fn capture_with_input<T: AsRef<[u8]>>(&self, input: T) -> Result<running::Output, RunnyError> {
+        let mut running = self.start_session(true)?;
+        Ok(running.exchange(Some(input.as_ref().to_vec()), None)?.0)
+    }
+
+    /// Run the command and return everything it wrote to stdout and stderr
+    /// along with its exit code, or TimedOut if it doesn't finish within
+    /// `timeout`, or the timeout set by timeout() if that's sooner.
+    pub fn capture_timeout(&self, timeout: Duration) -> Result<running::Output, RunnyError> {
+        let mut running = self.start()?;
+        match running.exchange(None, Some(timeout))? {
+            (output, false) => Ok(output),
+            (output, true) => Err(RunnyError::TimedOut(output)),
+        }
+    }
+
+    /// Run the command, copying its stdout and stderr into the given writers
+    /// as it runs, and return its exit code.
+    pub fn capture_stream<W: Write + Send>(&self,
+                                           mut stdout: W,
+                                           mut stderr: W)
+                                           -> Result<i32, RunnyError> {
+        let mut running = self.start()?;
+        let mut output = running.take_output();
+        let mut error = running.take_error();
+
+        let (output_result, error_result) = thread::scope(|s| {
+            let output_thr = s.spawn(|| io::copy(&mut output, &mut stdout));
+            let error_thr = s.spawn(|| io::copy(&mut error, &mut stderr));
+            (output_thr.join().unwrap(), error_thr.join().unwrap())
+        });
+        output_result?;
+        error_result?;
+        Ok(running.result())
+    }
+
+    /// Replace the child's supplementary groups.  This needs CAP_SETGID.
+    #[cfg(target_os = "linux")]
+    pub fn with_supplementary_groups(&mut self, gids: Vec<u32>) -> &mut Runny {
+        self.supplementary_groups = Some(gids);
+        self
+    }
+
+    /// Give the child the supplementary groups that `name` belongs to.
+    #[cfg(target_os = "linux")]
+    pub fn with_groups_from_user(&mut self, name: &str) -> Result<&mut Runny, RunnyError> {
+        let gids = user_groups(name)?;
+        Ok(self.with_supplementary_groups(gids))
+    }
+
+    /// Set environment variables whose values are sensitive.  They're held as
+    /// SecretStrings, so they don't show up in the Runny's Debug output.
+    /// They're kept for as long as the Runny is, since it may be started more
+    /// than once, and zeroed when it's dropped.  On unix they're never given
+    /// to Command: the child's environment is built in buffers that are
+    /// zeroed once it has started, and the child installs it just before
+    /// exec().  Elsewhere, Command's copies are freed without being zeroed.
+    pub fn env_secrets<V: Into<String>>(&mut self, secrets: HashMap<String, V>) -> &mut Runny {
+        self.env_secrets
+            .extend(secrets.into_iter().map(|(key, value)| (key, SecretString::from(value.into()))));
+        self
+    }
+
+    /// Append arguments from the environment variable `var`, split the same
+    /// way as the command string, in the style of RUSTFLAGS.  The variable is
+    /// read when the command is started, and adds nothing if it isn't set.
+    /// It has to be valid UTF-8.
+    pub fn with_arg_env(&mut self, var: &str) -> &mut Runny {
+        self.arg_env = Some(var.to_owned());
+        self
+    }
+
+    /// Substitute `$VAR` and `${VAR}` in the command string with values from
+    /// the environment before it is split into arguments.
+    pub fn with_env_expansion(&mut self) -> &mut Runny {
+        self.env_expansion = true;
+        self
+    }
+
+    /// Replace `$VAR` and `${VAR}` in `value` with the contents of the named
+    /// environment variable, or with nothing if it isn't set.  Quoting is not
+    /// taken into account, and a `$` that doesn't start a name is left alone.
+    pub fn env_expand(value: &str) -> String {
+        fn is_name_char(c: char) -> bool {
+            c.is_ascii_alphanumeric() || c == '_'
+        }
+
+        let mut expanded = String::new();
+        let mut rest = value;
+        while let Some(idx) = rest.find('$') {
+            expanded.push_str(&rest[..idx]);
+            let after = &rest[idx + 1..];
+            let (name, remainder) = if after.starts_with('{') {
+                match after.find('}') {
+                    Some(end) => (&after[1..end], &after[end + 1..]),
+                    None => ("", after),
+                }
+            } else {
+                let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            };
+
+            if name.is_empty() {
+                expanded.push('$');
+            } else {
+                expanded.push_str(&env_var(name).and_then(|v| v.into_string().ok()).unwrap_or_default());
+            }
+            rest = remainder;
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    /// Look the program up in the configured path (or $PATH if none was
+    /// given) now, rather than leaving it to exec() at start() time.
+    pub fn with_resolved_path(&mut self) -> Result<&mut Runny, RunnyError> {
+        let program = self.args()?.remove(0);
+        match self.which(&program) {
+            Some(path) => {
+                self.resolved_program = Some(path);
+                Ok(self)
+            }
+            None => {
+                let msg = format!("{} not found in path", program.to_string_lossy());
+                Err(RunnyError::RunnyIoError(io::Error::new(io::ErrorKind::NotFound, msg)))
+            }
+        }
+    }
+
+    fn which(&self, program: &OsStr) -> Option<PathBuf> {
+        let program = Path::new(program);
+        if program.components().count() > 1 {
+            return Self::is_executable(program).then(|| program.to_path_buf());
+        }
+
+        let dirs = match self.search_path() {
+            Some(dirs) => dirs,
+            None => env::var_os("PATH").map(|p| env::split_paths(&p).collect()).unwrap_or_default(),
+        };
+        for dir in dirs {
+            let candidate = dir.join(program);
+            if Self::is_executable(&candidate) {
+                return Some(candidate);
+            }
+            #[cfg(windows)]
+            {
+                let candidate = candidate.with_extension("exe");
+                if Self::is_executable(&candidate) {
+                    return Some(candidate);
+                }
+            }
+        }
+        None
+    }
+
+    fn is_executable(path: &Path) -> bool {
+        match path.metadata() {
+            #[cfg(unix)]
+            Ok(m) => {
+                use std::os::unix::fs::PermissionsExt;
+                m.is_file() && m.permissions().mode() & 0o111 != 0
+            }
+            #[cfg(not(unix))]
+            Ok(m) => m.is_file(),
+            Err(_) => false,
+        }
+    }
+
+    fn args(&self) -> Result<Vec<OsString>, RunnyError> {
+        let mut args: Vec<OsString> = match self.argv {
+            Some(ref argv) => argv.clone(),
+            None if self.env_expansion => {
+                Self::make_command(&Self::env_expand(&self.cmd))?
+                    .into_iter()
+                    .map(OsString::from)
+                    .collect()
+            }
+            None => {
+                Self::make_command(self.cmd.as_str())?
+                    .into_iter()
+                    .map(OsString::from)
+                    .collect()
+            }
+        };
+        if let Some(ref var) = self.arg_env {
+            if let Some(value) = env_var(var) {
+                match value.to_str().and_then(Self::split_args) {
+                    Some(extra) => args.extend(extra.into_iter().map(OsString::from)),
+                    None => return Err(RunnyError::InvalidArgEnv(var.clone())),
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    fn make_command(cmd: &str) -> Result<Vec<String>, RunnyError> {
+        match Self::split_args(cmd) {
+            None => Err(RunnyError::NoCommandSpecified),
+            Some(s) => Ok(s),
+        }
+    }
+
+    /// Split `s` into arguments with shell quoting rules, except that
+    /// backslashes are kept as they are.
+    fn split_args(s: &str) -> Option<Vec<String>> {
+        shlex::split(&s.replace("\\", "\\\\"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[cfg(unix)]
+    use std::io::{BufRead, Write};
+    use std::time::Instant;
+
+    #[cfg(windows)]
+    extern crate winapi;
+    #[cfg(windows)]
+    extern crate user32;
+
+    /// Set `name` to `value`, or unset it, as far as Runny on this thread
+    /// can tell.
+    fn set_test_env(name: &str, value: Option<&str>) {
+        TEST_ENV.with(|vars| vars.borrow_mut().insert(name.to_owned(), value.map(str::to_owned)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn launch_echo() {
+        let mut running = Runny::new("/bin/echo -n 'Launch test echo works'").start().unwrap();
+        let mut simple_str = String::new();
+
+        running.read_to_string(&mut simple_str).unwrap();
+        assert_eq!(simple_str, "Launch test echo works");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_multi_lines() {
+        let mut running = Runny::new("/usr/bin/seq 1 5").start().unwrap();
+
+        let mut vec = vec![];
+        for line in io::BufReader::new(running.take_output()).lines() {
+            vec.push(line.unwrap());
+        }
+        assert_eq!(vec.len(), 5);
+        let vec_parsed: Vec<i32> = vec.iter().map(|x| x.parse().unwrap()).collect();
+        assert_eq!(vec_parsed, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_size() {
+        let mut running = Runny::new("/usr/bin/seq 1 100").start().unwrap();
+        assert_eq!(running.output_size().unwrap(), 292);
+
+        let mut running = Runny::new("/usr/bin/seq 1 100").start().unwrap();
+        assert_eq!(running.output_line_count().unwrap(), 100);
+
+        let mut running = Runny::new("/bin/echo ' one two\tthree\n four '").start().unwrap();
+        assert_eq!(running.output_words_count().unwrap(), 4);
+
+        let mut running = Runny::new("/usr/bin/seq 1 10").start().unwrap();
+        assert_eq!(running.output_lines_count().unwrap(), 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn first_and_last_output_line() {
+        let mut running = Runny::new("/bin/bash -c 'echo; echo start; echo middle; echo done; echo'")
+            .start()
+            .unwrap();
+        assert_eq!(running.last_output_line().unwrap().unwrap(), "done");
+        let mut running = Runny::new("/bin/bash -c 'echo; echo start; echo done'").start().unwrap();
+        assert_eq!(running.first_output_line().unwrap().unwrap(), "start");
+        let mut running = Runny::new("/bin/true").start().unwrap();
+        assert_eq!(running.last_output_line().unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_lines_matching() {
+        let mut running = Runny::new("/usr/bin/seq 1 30").start().unwrap();
+        assert_eq!(running.output_lines_matching("2").unwrap(),
+                   vec!["2", "12", "20", "21", "22", "23", "24", "25", "26", "27", "28", "29"]);
+    }
+
+    #[cfg(all(unix, feature = "regex"))]
+    #[test]
+    fn output_lines_matching_re() {
+        let mut running = Runny::new("/usr/bin/seq 1 30").start().unwrap();
+        assert_eq!(running.output_lines_matching_re("^2.$").unwrap(),
+                   vec!["20", "21", "22", "23", "24", "25", "26", "27", "28", "29"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_diff() {
+        let mut running = Runny::new("/usr/bin/seq 1 3").start().unwrap();
+        assert_eq!(running.output_diff(b"1\n2\n3\n").unwrap(), None);
+
+        let mut running = Runny::new("/usr/bin/seq 1 3").start().unwrap();
+        assert_eq!(running.output_diff(b"1\n4\n3\n").unwrap(),
+                   Some(running::DiffResult {
+                       line: 2,
+                       expected: "4\n".to_owned(),
+                       actual: "2\n".to_owned(),
+                   }));
+
+        let mut running = Runny::new("/usr/bin/seq 1 3").start().unwrap();
+        assert_eq!(running.output_diff(b"1\n2\n3\n4\n").unwrap(),
+                   Some(running::DiffResult {
+                       line: 4,
+                       expected: "4\n".to_owned(),
+                       actual: "".to_owned(),
+                   }));
+
+        Runny::new("/usr/bin/seq 1 3").start().unwrap().assert_output_eq(b"1\n2\n3\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic(expected = "output differs at line 3")]
+    fn assert_output_eq() {
+        Runny::new("/usr/bin/seq 1 3").start().unwrap().assert_output_eq(b"1\n2\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn assert_exit() {
+        Runny::new("/bin/bash -c 'echo hello world'")
+            .start()
+            .unwrap()
+            .assert_exit_success()
+            .assert_output_contains("lo wo");
+        Runny::new("/bin/bash -c 'exit 3'").start().unwrap().assert_exit_code(3).assert_exit_failure();
+        Runny::new("/bin/bash -c 'kill -TERM $$'").start().unwrap().assert_signaled();
+
+        // More output than the pty holds, which has to be read while waiting.
+        Runny::new("/usr/bin/seq 1 100000")
+            .start()
+            .unwrap()
+            .assert_exit_success()
+            .assert_output_contains("\n99999\n100000");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic(expected = "`/bin/true` exited with 0, expected it to fail")]
+    fn assert_exit_failure() {
+        Runny::new("/bin/true").start().unwrap().assert_exit_failure();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic(expected = "`/bin/bash -c echo oops >&2; exit 1` exited with 1, expected 0, with stderr:\noops")]
+    fn assert_exit_success() {
+        Runny::new("/bin/bash -c 'echo oops >&2; exit 1'").start().unwrap().assert_exit_success();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tee_output() {
+        let path = env::temp_dir().join(format!("runny-tee-{}", std::process::id()));
+        let mut running = Runny::new("/usr/bin/seq 1 5").start().unwrap();
+        running.tee_output_to(&path).unwrap();
+
+        let mut s = String::new();
+        running.take_output().read_to_string(&mut s).unwrap();
+        let mut teed = String::new();
+        File::open(&path).unwrap().read_to_string(&mut teed).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(s, "1\n2\n3\n4\n5\n");
+        assert_eq!(teed, s);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "ptrace"))]
+    #[test]
+    fn redirect_stdout_to_file() {
+        fn wait_for_contents(path: &Path, expected: &str) {
+            for _ in 0..100 {
+                if std::fs::read_to_string(path).unwrap_or_default() == expected {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(std::fs::read_to_string(path).unwrap(), expected);
+        }
+
+        // Tracing a process we didn't start needs privilege under Yama.
+        let scope = std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope").unwrap_or_default();
+        if !nix::unistd::geteuid().is_root() && scope.trim() != "" && scope.trim() != "0" {
+            return;
+        }
+
+        let first = env::temp_dir().join(format!("runny-log1-{}", std::process::id()));
+        let second = env::temp_dir().join(format!("runny-log2-{}", std::process::id()));
+        std::fs::write(&second, "zero\n").unwrap();
+
+        let mut running = Runny::new("/bin/bash -c 'echo one; read x; echo two; read x; echo three'")
+            .start()
+            .unwrap();
+        let mut one = [0; 4];
+        running.read_exact(&mut one).unwrap();
+        assert_eq!(&one, b"one\n");
+
+        running.redirect_stdout_to_file(&first, false).unwrap();
+        writeln!(running, "go").unwrap();
+        wait_for_contents(&first, "two\n");
+
+        running.redirect_stdout_to_file(&second, true).unwrap();
+        writeln!(running, "go").unwrap();
+        assert_eq!(running.wait().unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "zero\nthree\n");
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "two\n");
+
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stdout_to_channel_lines() {
+        let mut running = Runny::new("/bin/bash -c 'echo one; printf \"\\xff\\n\"; echo -n two'")
+            .start()
+            .unwrap();
+        let lines: Vec<_> = running.stdout_to_channel_lines().iter().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].as_ref().unwrap(), "one");
+        assert_eq!(lines[1].as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(lines[2].as_ref().unwrap(), "two");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_lines_since() {
+        fn wait_for_line(running: &mut running::Running, last_line: u64, line: u64) -> Vec<String> {
+            for _ in 0..100 {
+                let (lines, mark) = running.output_lines_since(last_line).unwrap();
+                if mark >= line {
+                    assert_eq!(mark, line);
+                    return lines;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("line {} never arrived", line);
+        }
+
+        let mut running = Runny::new("/bin/bash -c 'seq 1 3; read x; seq 4 5; read x'").start().unwrap();
+        assert_eq!(wait_for_line(&mut running, 0, 3), ["1", "2", "3"]);
+        writeln!(running, "go").unwrap();
+        assert_eq!(wait_for_line(&mut running, 3, 5), ["4", "5"]);
+        let (lines, mark) = running.output_lines_since(1).unwrap();
+        assert_eq!(lines, ["2", "3", "4", "5"]);
+        assert_eq!(mark, 5);
+        assert!(running.output_lines_since(5).unwrap().0.is_empty());
+        writeln!(running, "go").unwrap();
+
+        let mut running = Runny::new("/usr/bin/seq 1 10").start().unwrap();
+        running.buffer_output_lines(3).unwrap();
+        assert_eq!(wait_for_line(&mut running, 0, 10), ["8", "9", "10"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn watch_output() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n out; echo -n err 1>&2'")
+            .start()
+            .unwrap();
+        let output = Arc::new(Mutex::new(vec![]));
+        let error = Arc::new(Mutex::new(vec![]));
+
+        let output_thr = output.clone();
+        let error_thr = error.clone();
+        let output_watcher =
+            running.watch_output(move |buf| output_thr.lock().unwrap().extend_from_slice(buf)).unwrap();
+        let error_watcher =
+            running.watch_error(move |buf| error_thr.lock().unwrap().extend_from_slice(buf)).unwrap();
+        output_watcher.join().unwrap();
+        error_watcher.join().unwrap();
+        assert!(running.watch_output(|_| ()).is_err());
+
+        assert_eq!(*output.lock().unwrap(), b"out");
+        assert_eq!(*error.lock().unwrap(), b"err");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn terminate_works() {
+        let timeout_secs = 5;
+
+        let mut running = Runny::new("/bin/bash -c 'sleep 1000'").start().unwrap();
+
+        let mut s = String::new();
+        let start_time = Instant::now();
+        running.terminate(Some(Duration::from_secs(timeout_secs))).unwrap();
+        let end_time = Instant::now();
+        running.read_to_string(&mut s).unwrap();
+
+        assert_eq!(s, "");
+        // Give one extra second for timeout, to account for plumbing.
+        assert!(end_time.duration_since(start_time) < Duration::from_secs(timeout_secs + 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_works() {
+        let timeout_secs = 3;
+        let start_time = Instant::now();
+
+        let mut running = Runny::new("/bin/bash -c 'echo -n Hi there; sleep 1000; echo -n Bye \
+                                      there'")
+            .timeout(Duration::from_secs(timeout_secs))
+            .start()
+            .unwrap();
+
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        let end_time = Instant::now();
+
+        // Give one extra second for timeout, to account for plumbing.
+        assert!(end_time.duration_since(start_time) < Duration::from_secs(timeout_secs + 1));
+        assert!(end_time.duration_since(start_time) > Duration::from_secs(timeout_secs - 1));
+        assert_eq!(s, "Hi there");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retries() {
+        let path = env::temp_dir().join(format!("runny-retries-{}", std::process::id()));
+        let cmd = format!("/bin/bash -c 'n=$(cat {0} 2>/dev/null || echo 0); echo $((n + 1)) > {0}; \
+                           echo -n attempt$n; echo -n err$n >&2; [ $n -ge 2 ]'",
+                          path.display());
+        let mut running = Runny::new(&cmd).retries(5, Duration::from_millis(10)).start().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(running.attempt_exit_codes(), [1, 1, 0]);
+        assert_eq!(running.result(), 0);
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "attempt2");
+        let mut s = String::new();
+        running.take_error().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "err2");
+
+        let running = Runny::new("/bin/bash -c 'exit 3'").retries(1, Duration::from_millis(10)).start().unwrap();
+        assert_eq!(running.attempt_exit_codes(), [3, 3]);
+        assert_eq!(running.result(), 3);
+
+        let running = Runny::new("/bin/true").start().unwrap();
+        assert!(running.attempt_exit_codes().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn set_timeout() {
+        let start_time = Instant::now();
+        let running = Runny::new("/bin/sleep 1000")
+            .timeout(Duration::from_secs(1000))
+            .start()
+            .unwrap();
+        running.set_timeout(Duration::from_secs(2));
+        running.wait().unwrap();
+        let end_time = Instant::now();
+
+        assert!(end_time.duration_since(start_time) < Duration::from_secs(3));
+        assert!(end_time.duration_since(start_time) > Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_write() {
+        let mut running = Runny::new("/bin/bash -c 'echo Input:; read foo; echo Got string: \
+                                  -$foo-; sleep 1; echo End'")
+            .start()
+            .unwrap();
+        let mut input = running.take_input();
+        let mut output = running.take_output();
+        writeln!(input, "bar").unwrap();
+
+        let mut result = String::new();
+        output.read_to_string(&mut result).unwrap();
+
+        running.terminate(None).unwrap();
+        assert_eq!(result, "Input:\nGot string: -bar-\nEnd\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_echo() {
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'")
+            .with_pty_echo(true)
+            .start()
+            .unwrap();
+        writeln!(running, "bar").unwrap();
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "bar\nGot bar");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn input_filter() {
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'")
+            .input_filter(|buf| buf.to_ascii_uppercase())
+            .start()
+            .unwrap();
+        writeln!(running, "bar").unwrap();
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "Got BAR");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn line_endings() {
+        use running::LineEnding;
+
+        // Sockets rather than a pty, which can report EOF once the child has
+        // exited but before all of its output has been read.
+        let mut running = Runny::new("/bin/bash -c 'read a; read b; echo -n \"$a|$b\" | od -An -c'")
+            .with_stdio_sockets()
+            .with_stdin_line_ending(LineEnding::Windows)
+            .start()
+            .unwrap();
+        running.write_all(b"one\r\ntwo\n").unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.split_whitespace().collect::<String>(), "one\\r|two\\r");
+
+        // A trailing "\r" is held back in case "\n" follows, until the input
+        // is flushed or closed.
+        let mut running = Runny::new("/bin/sh -c 'od -An -c'")
+            .with_stdio_sockets()
+            .with_stdin_line_ending(LineEnding::Unix)
+            .start()
+            .unwrap();
+        running.write_all(b"one\r").unwrap();
+        running.flush().unwrap();
+        running.write_close_stdin(b"\ntwo\r\nthree\r").unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.split_whitespace().collect::<String>(), "one\\r\\ntwo\\nthree\\r");
+
+        let mut running = Runny::new("/bin/sh -c 'head -c 2 | od -An -c'")
+            .with_stdio_sockets()
+            .with_stdin_line_ending(LineEnding::Unix)
+            .start()
+            .unwrap();
+        let mut input = running.take_input();
+        input.write_all(b"x\r").unwrap();
+        drop(input);
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.split_whitespace().collect::<String>(), "x\\r");
+
+        let mut running = Runny::new("/bin/bash -c 'printf \"one\\r\\ntwo\\rthree\\n\"'")
+            .with_stdio_sockets()
+            .with_stdout_line_ending(LineEnding::Unix)
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "one\ntwo\rthree\n");
+
+        let mut running = Runny::new("/bin/bash -c 'printf \"one\\ntwo\\r\\n\"'")
+            .with_stdio_sockets()
+            .with_stdout_line_ending(LineEnding::Windows)
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "one\r\ntwo\r\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_integers() {
+        let mut running = Runny::new("/bin/bash -c 'head -c 13 | od -An -tx1'").start().unwrap();
+        let mut input = running.take_input();
+        input.write_u8(0x41).unwrap();
+        input.write_u16_le(0x4243).unwrap();
+        input.write_u16_be(0x4445).unwrap();
+        input.write_u32_le(0x46474849).unwrap();
+        input.write_u32_be(0x4a4b4c4d).unwrap();
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result.split_whitespace().collect::<Vec<_>>(),
+                   ["41", "43", "42", "44", "45", "49", "48", "47", "46", "4a", "4b", "4c", "4d"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_bytes_to_stdin() {
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'").start().unwrap();
+        assert_eq!(running.send_bytes_to_stdin(b"bar\n").unwrap(), 4);
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "Got bar");
+
+        running.take_input();
+        assert!(running.send_bytes_to_stdin(b"bar\n").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_close_stdin() {
+        let mut running = Runny::new("/usr/bin/wc -c").with_stdio_sockets().start().unwrap();
+        running.write_close_stdin(b"hello world").unwrap();
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result.trim(), "11");
+        assert!(running.write_close_stdin(b"again").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stdin_lines_written() {
+        let mut running = Runny::new("/bin/bash -c 'read a; read b; echo -n $a$b'").start().unwrap();
+        assert_eq!(running.stdin_lines_written(), 0);
+        running.send_bytes_to_stdin(b"foo\nba").unwrap();
+        assert_eq!(running.stdin_lines_written(), 1);
+        writeln!(running.take_input(), "r").unwrap();
+        assert_eq!(running.stdin_lines_written(), 2);
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "foobar");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_file_to_stdin() {
+        let path = env::temp_dir().join(format!("runny-stdin-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(b"bar\n").unwrap();
+
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'").start().unwrap();
+        assert_eq!(running.write_file_to_stdin(&path).unwrap(), 4);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut result = String::new();
+        running.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "Got bar");
+        assert!(running.input().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_write_err() {
+        let mut running = Runny::new("/bin/bash -c 'echo Input:; read foo; echo Got string: \
+                                  -$foo-; echo -n Error string 1>&2; sleep 1; echo Cool'")
+            .timeout(Duration::from_secs(5))
+            .start()
+            .unwrap();
+        let mut input = running.take_input();
+        let mut output = running.take_output();
+        let mut error = running.take_error();
+
+        writeln!(input, "bar").unwrap();
+
+        let mut result = String::new();
+        let mut err_result = String::new();
+
+        output.read_to_string(&mut result).unwrap();
+        error.read_to_string(&mut err_result).unwrap();
+
+        running.terminate(None).unwrap();
+        println!("stdout: {}", result);
+        println!("stderr: {}", err_result);
+        assert_eq!(result, "Input:\nGot string: -bar-\nCool\n");
+        assert_eq!(err_result, "Error string");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_all_output() {
+        let mut running = Runny::new("/bin/bash -c 'head -c 200000 /dev/zero 1>&2; echo -n done'")
+            .timeout(Duration::from_secs(5))
+            .start()
+            .unwrap();
+        let (output, error) = running.wait_all_output().unwrap();
+        assert_eq!(output, b"done");
+        assert_eq!(error.len(), 200000);
+        assert_eq!(running.result(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_ctrl() {
+        let mut running = Runny::new("/bin/bash -c 'head -c 2 | od -An -tx1'").start().unwrap();
+        assert!(running.write_ctrl('1').is_err());
+        running.send_sigint().unwrap();
+        running.write_ctrl('D').unwrap();
+
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.trim(), "03 04");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn combined_output() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n out; sleep 0.5; echo -n err 1>&2; \
+                                      sleep 0.5; echo -n out'")
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.take_combined_output().unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "outerrout");
+        assert!(running.take_combined_output().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pipe_flags() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n one >&2; echo -n two >&2'")
+            .with_pipe_flags(nix::fcntl::O_DIRECT)
+            .start()
+            .unwrap();
+        running.wait().unwrap();
+        let mut error = running.take_error();
+        let mut buf = [0; 100];
+        let n = error.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"one");
+        let n = error.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"two");
+
+        let err = Runny::new("/bin/true").with_pipe_flags(nix::fcntl::O_NONBLOCK).start().unwrap_err();
+        match err {
+            RunnyError::RunnyIoError(e) => assert_eq!(e.raw_os_error(), Some(nix::libc::EINVAL)),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pipe_limit() {
+        use std::thread;
+
+        let mut running = Runny::new("/bin/bash -c 'head -c 8192 /dev/zero 1>&2'")
+            .with_pipe_limit(4096)
+            .timeout(Duration::from_secs(5))
+            .start()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        assert!(running.time_since_exit().is_none());
+
+        let mut error = vec![];
+        running.take_error().read_to_end(&mut error).unwrap();
+        assert_eq!(error.len(), 8192);
+        assert_eq!(running.result(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn capture_with_input() {
+        let output = Runny::new("/usr/bin/rev").capture_with_input("hello\nworld\n").unwrap();
+        assert_eq!(output.stdout, b"olleh\ndlrow\n");
+        assert_eq!(output.exit_code, 0);
+
+        let output = Runny::new("/usr/bin/sort").capture_with_input(b"b\nc\na\n").unwrap();
+        assert_eq!(output.stdout, b"a\nb\nc\n");
+
+        let output = Runny::new("/usr/bin/wc -l").capture_with_input(vec![b'\n'; 100000]).unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "100000");
+
+        let output = Runny::new("/bin/bash -c 'cat >&2; exit 3'").capture_with_input("err").unwrap();
+        assert_eq!(output.stdout, b"");
+        assert_eq!(output.stderr, b"err");
+        assert_eq!(output.exit_code, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn capture_timeout() {
+        let output = Runny::new("/bin/bash -c 'echo -n out; echo -n err >&2; exit 3'")
+            .capture_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(output.stdout, b"out");
+        assert_eq!(output.stderr, b"err");
+        assert_eq!(output.exit_code, 3);
+
+        match Runny::new("/bin/bash -c 'echo -n partial; sleep 1000'")
+            .capture_timeout(Duration::from_millis(200)) {
+            Err(RunnyError::TimedOut(output)) => assert_eq!(output.stdout, b"partial"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let start = Instant::now();
+        match Runny::new("/bin/sleep 1000")
+            .timeout(Duration::from_millis(200))
+            .capture_timeout(Duration::from_secs(10)) {
+            Err(RunnyError::TimedOut(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn communicate() {
+        let mut running = Runny::new("/bin/bash -c 'cat; echo -n oops >&2'")
+            .with_stdio_sockets()
+            .start()
+            .unwrap();
+        let (stdout, stderr) = running.communicate(Some(b"hello"), Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stdout, b"hello");
+        assert_eq!(stderr, b"oops");
+
+        let mut running = Runny::new("/bin/bash -c 'echo -n started; sleep 1000'").start().unwrap();
+        match running.communicate(None, Some(Duration::from_millis(200))) {
+            Err(running::RunningError::TimedOut(output)) => assert_eq!(output.stdout, b"started"),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+
+        // A pty can't be closed, so cat would wait for more input forever.
+        let mut running = Runny::new("/bin/cat").start().unwrap();
+        match running.communicate(Some(b"hello"), None) {
+            Err(running::RunningError::RunningIoError(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        running.terminate(None).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn capture_stream() {
+        let mut output = vec![];
+        let mut error = vec![];
+        let result = Runny::new("/bin/bash -c 'echo -n out; echo -n err 1>&2; exit 4'")
+            .capture_stream(&mut output, &mut error)
+            .unwrap();
+        assert_eq!(result, 4);
+        assert_eq!(output, b"out");
+        assert_eq!(error, b"err");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn terminate_and_collect() {
+        // Sleep in the background, so that bash doesn't report it being
+        // terminated along with bash itself.
+        let mut running = Runny::new("/bin/bash -c 'trap \"echo -n bye; echo -n err 1>&2; exit 3\" \
+                                      TERM; echo ready; sleep 1000 & wait'")
+            .start()
+            .unwrap();
+        let mut ready = [0; 6];
+        running.read_exact(&mut ready).unwrap();
+        assert_eq!(&ready, b"ready\n");
+
+        let output = running.terminate_and_collect(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(output.stdout, b"bye");
+        assert_eq!(output.stderr, b"err");
+        assert_eq!(output.exit_code, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_codes() {
+        assert_eq!(Runny::new("/bin/true").start().unwrap().result(), 0);
+        assert_ne!(Runny::new("/bin/false").start().unwrap().result(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn try_result() {
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        assert_eq!(running.try_result(), None);
+        running.terminate(None).unwrap();
+        assert!(running.try_result().is_some());
+
+        let running = Runny::new("/bin/false").start().unwrap();
+        running.wait().unwrap();
+        assert_eq!(running.try_result(), Some(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_string() {
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        assert_eq!(running.status_string(), format!("pid={} running", running.pid()));
+        running.terminate(None).unwrap();
+        assert_eq!(running.status_string(), format!("pid={} killed(SIGTERM)", running.pid()));
+
+        let running = Runny::new("/bin/bash -c 'exit 3'").start().unwrap();
+        running.wait().unwrap();
+        assert_eq!(running.status_string(), format!("pid={} exited(3)", running.pid()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_signal() {
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        assert_eq!(running.exit_signal(), None);
+        running.terminate(None).unwrap();
+        assert_eq!(running.exit_signal(), Some(nix::sys::signal::SIGTERM));
+
+        let running = Runny::new("/bin/bash -c 'exit 3'").start().unwrap();
+        running.wait().unwrap();
+        assert_eq!(running.exit_signal(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn time_since_start() {
+        let running = Runny::new("/bin/sleep 1").start().unwrap();
+        assert!(running.time_since_exit().is_none());
+        running.wait().unwrap();
+
+        let since_exit = running.time_since_exit().unwrap();
+        assert!(running.time_since_start() >= Duration::from_secs(1));
+        assert!(running.time_since_start() > since_exit);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_waiter_wait() {
+        let cmd = Runny::new("/bin/bash -c 'sleep 2'");
+
+        let start_time = Instant::now();
+        let run = cmd.start().unwrap();
+        let waiter = run.waiter();
+        waiter.wait();
+        let end_time = Instant::now();
+
+        // Give one extra second for timeout, to account for plumbing.
+        assert!(end_time.duration_since(start_time) < Duration::from_secs(3));
+        assert!(end_time.duration_since(start_time) > Duration::from_secs(1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn running_waiter_result() {
+        let run = Runny::new("/bin/bash -c 'exit 1'").start().unwrap();
+        let waiter = run.waiter();
+        waiter.wait();
+        assert_eq!(waiter.result(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_stderr() {
+        let mut run = Runny::new("/bin/bash -c 'echo -n error-test 1>&2'").start().unwrap();
+        let mut s = String::new();
+        let mut stderr = run.take_error();
+        stderr.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "error-test");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn netstat() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cmd = format!("/bin/bash -c 'exec 3<>/dev/tcp/127.0.0.1/{}; echo -n connected; sleep 1000'",
+                          addr.port());
+        let mut running = Runny::new(&cmd).start().unwrap();
+        let mut buf = [0; 9];
+        running.read_exact(&mut buf).unwrap();
+        let (_stream, peer) = listener.accept().unwrap();
+
+        let connections = running.netstat().unwrap();
+        running.terminate(None).unwrap();
+        assert_eq!(connections,
+                   vec![running::NetConnection {
+                            local_addr: peer,
+                            remote_addr: addr,
+                            state: "ESTABLISHED".to_owned(),
+                        }]);
+        assert!(running.netstat().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_to_cpu() {
+        let mut running = Runny::new("/bin/bash -c 'read x; grep Cpus_allowed_list /proc/self/status'")
+            .start()
+            .unwrap();
+        running.bind_to_cpu(0).unwrap();
+        writeln!(running, "go").unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.split_whitespace().collect::<Vec<_>>(), ["Cpus_allowed_list:", "0"]);
+
+        running.wait().unwrap();
+        assert!(running.bind_to_cpu(0).is_err());
+        assert!(Runny::new("/bin/sleep 1000").start().unwrap().bind_to_cpu(100000).is_err());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "ptrace"))]
+    #[test]
+    fn inject_env() {
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        running.inject_env("PATH", "/changed").unwrap();
+        assert_eq!(running.environ_var("PATH").unwrap().unwrap(), "/changed");
+        assert!(running.inject_env("RUNNY_NOT_SET", "x").unwrap_err().kind() == io::ErrorKind::NotFound);
+        let long = "x".repeat(std::env::var("PATH").unwrap().len() + 1);
+        assert!(running.inject_env("PATH", &long).unwrap_err().kind() == io::ErrorKind::InvalidInput);
+        running.terminate(None).unwrap();
+    }
+
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    #[test]
+    #[ignore = "needs tracefs and perf_event access"]
+    fn syscall_count() {
+        assert!(Runny::new("/bin/true").start().unwrap().syscall_count().is_err());
+
+        let running = Runny::new("/bin/sh -c 'sleep 0.5; echo hi'").count_syscalls().start().unwrap();
+        running.wait().unwrap();
+        let counts = running.syscall_count().unwrap();
+        // The shell's own startup is counted, as is the sleep it runs.
+        assert!(counts["brk"] >= 1);
+        assert!(counts["execve"] >= 1);
+        assert!(counts["write"] >= 1);
+        assert!(counts["exit_group"] >= 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn signal_mask() {
+        // Signals are unblocked before exec(), and sleep doesn't block any.
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        let mask = running.signal_mask().unwrap();
+        assert!(!mask.contains(nix::sys::signal::SIGTERM));
+        assert!(!mask.contains(nix::sys::signal::SIGINT));
+        running.terminate(None).unwrap();
+        assert!(running.signal_mask().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn core_dump_path() {
+        let running = Runny::new("/bin/true").start().unwrap();
+        running.wait().unwrap();
+        assert_eq!(running.core_dump_path().unwrap(), None);
+
+        let dir = env::temp_dir().join(format!("runny-core-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let running = Runny::new("/bin/bash -c 'ulimit -c unlimited; sleep 0.1; kill -ABRT $$'")
+            .directory(&Some(dir.clone()))
+            .start()
+            .unwrap();
+        running.wait().unwrap();
+        // Where the core goes, if anywhere, depends on how this machine is set up.
+        if let Some(path) = running.core_dump_path().unwrap() {
+            assert!(path.starts_with(&dir));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn expand_core_pattern() {
+        use running::expand_core_pattern;
+
+        let expand = |pattern, uses_pid, comm| expand_core_pattern(pattern, uses_pid, 42, 6, 1700000000, "box", comm);
+        assert_eq!(expand("core", false, None).unwrap(), "core");
+        assert_eq!(expand("core", true, None).unwrap(), "core.42");
+        assert_eq!(expand("core.%p", true, None).unwrap(), "core.42");
+        assert_eq!(expand("/var/crash/%e-%h-%t-%s", false, Some("sleep")).unwrap(),
+                   "/var/crash/sleep-box-1700000000-6");
+        assert_eq!(expand("%e.%i", true, Some("bash")).unwrap(), "bash.42");
+        assert_eq!(expand("100%%-%p", false, None).unwrap(), "100%-42");
+        assert_eq!(expand("%e", false, None), None);
+        assert_eq!(expand("core.%u", false, None), None);
+        assert_eq!(expand("core%", false, None), None);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    #[test]
+    #[ignore = "needs perf_event access"]
+    fn perf_stats() {
+        use running::PerfEvent;
+
+        let events = vec![PerfEvent::TaskClock, PerfEvent::PageFaults];
+        let running = Runny::new("/bin/bash -c 'for i in $(seq 1000); do :; done'")
+            .with_perf_events(events)
+            .start()
+            .unwrap();
+        running.wait().unwrap();
+        let stats = running.perf_stats().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert!(stats[&PerfEvent::TaskClock] > 0);
+        assert!(stats[&PerfEvent::PageFaults] > 0);
+        assert!(Runny::new("/bin/true").start().unwrap().perf_stats().unwrap().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn memory_limit_bytes() {
+        // Whether there is a limit to read depends on how cgroups are set up
+        // on this machine.
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        match running.memory_limit_bytes() {
+            Ok(limit) => assert!(limit > 0),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        }
+        running.terminate(None).unwrap();
+        assert!(running.memory_limit_bytes().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cgroup_paths() {
+        use running::{cgroup_v1_path, cgroup_v2_path, parse_memory_limit};
+
+        let cgroups = "12:memory:/user.slice/session-1.scope\n\
+                       11:cpu,cpuacct:/user.slice\n\
+                       0::/user.slice/user-1000.slice/session-1.scope\n";
+        let mountinfo = "22 1 0:21 / /proc rw,nosuid - proc proc rw\n\
+                         30 25 0:26 / /sys/fs/cgroup rw,nosuid shared:4 - cgroup2 cgroup2 rw,nsdelegate\n\
+                         35 25 0:31 / /sys/fs/cgroup/cpu,cpuacct rw shared:9 - cgroup cgroup rw,cpu,cpuacct\n\
+                         36 25 0:32 / /sys/fs/cgroup/memory rw shared:10 - cgroup cgroup rw,memory\n";
+
+        assert_eq!(cgroup_v2_path(cgroups, mountinfo, "memory.max").unwrap(),
+                   Path::new("/sys/fs/cgroup/user.slice/user-1000.slice/session-1.scope/memory.max"));
+        assert_eq!(cgroup_v1_path(cgroups, mountinfo, "memory", "memory.oom_control").unwrap(),
+                   Path::new("/sys/fs/cgroup/memory/user.slice/session-1.scope/memory.oom_control"));
+        assert_eq!(cgroup_v1_path(cgroups, mountinfo, "cpu", "cpu.shares").unwrap(),
+                   Path::new("/sys/fs/cgroup/cpu,cpuacct/user.slice/cpu.shares"));
+        assert_eq!(cgroup_v1_path(cgroups, mountinfo, "pids", "pids.max").unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+        assert_eq!(cgroup_v2_path("0::/\n", "22 1 0:21 / /proc rw - proc proc rw\n", "memory.max")
+                       .unwrap_err()
+                       .kind(),
+                   io::ErrorKind::NotFound);
+
+        assert_eq!(parse_memory_limit("max\n").unwrap(), u64::MAX);
+        assert_eq!(parse_memory_limit("536870912\n").unwrap(), 536870912);
+        assert_eq!(parse_memory_limit("lots\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs permission to create a memory cgroup"]
+    fn oom_kill_count() {
+        // Make a memory cgroup under our own, with cgroup v2 if the memory
+        // controller is there, or cgroup v1 otherwise.
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap();
+        let name = format!("runny-oom-{}", std::process::id());
+        let v2_mount = mounts.lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+            .find(|fields| fields.len() > 2 && fields[2] == "cgroup2")
+            .map(|fields| PathBuf::from(fields[1]));
+        let v2_parent = match (cgroups.lines().find_map(|l| l.strip_prefix("0::")), v2_mount) {
+            (Some(path), Some(mount)) => Some(mount.join(path.trim_start_matches('/')))
+                .filter(|parent| {
+                    std::fs::read_to_string(parent.join("cgroup.controllers"))
+                        .map(|c| c.split_whitespace().any(|c| c == "memory"))
+                        .unwrap_or(false)
+                }),
+            _ => None,
+        };
+        let cgroup = match v2_parent {
+            Some(parent) => {
+                std::fs::write(parent.join("cgroup.subtree_control"), "+memory").ok();
+                let cgroup = parent.join(&name);
+                std::fs::create_dir(&cgroup).unwrap();
+                std::fs::write(cgroup.join("memory.max"), "16M").unwrap();
+                std::fs::write(cgroup.join("memory.swap.max"), "0").ok();
+                cgroup
+            }
+            None => {
+                let path = cgroups.lines()
+                    .find_map(|l| l.split_once(":memory:"))
+                    .expect("no memory cgroup controller")
+                    .1;
+                let cgroup = Path::new("/sys/fs/cgroup/memory").join(path.trim_start_matches('/')).join(&name);
+                std::fs::create_dir(&cgroup).unwrap();
+                std::fs::write(cgroup.join("memory.limit_in_bytes"), "16M").unwrap();
+                std::fs::write(cgroup.join("memory.memsw.limit_in_bytes"), "16M").ok();
+                cgroup
+            }
+        };
+
+        let mut running = Runny::new("/bin/bash -c 'read x; (y=$(head -c 100000000 /dev/zero | tr \"\\0\" a)); \
+                                      echo -n done; read x'")
+            .start()
+            .unwrap();
+        std::fs::write(cgroup.join("cgroup.procs"), running.pid().to_string()).unwrap();
+        assert_eq!(running.oom_kill_count().unwrap(), 0);
+        writeln!(running, "go").unwrap();
+        let mut buf = [0; 4];
+        running.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"done");
+        assert!(running.oom_kill_count().unwrap() >= 1);
+
+        running.terminate(None).unwrap();
+        assert!(running.oom_kill_count().is_err());
+        for _ in 0..100 {
+            if std::fs::remove_dir(&cgroup).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn child_threads() {
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        assert_eq!(running.child_threads().unwrap(), vec![running.pid() as u32]);
+        assert_eq!(running.child_thread_count().unwrap(), 1);
+        running.terminate(None).unwrap();
+        assert!(running.child_threads().unwrap().is_empty());
+        assert_eq!(running.child_thread_count().unwrap(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn child_exe_path() {
+        let running = Runny::new("/bin/sleep 5").start().unwrap();
+        let expected = std::fs::canonicalize("/bin/sleep").unwrap();
+        assert_eq!(running.child_exe_path().unwrap(), expected);
+
+        running.terminate(None).unwrap();
+        assert!(running.child_exe_path().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn io_counters() {
+        let mut running = Runny::new("/bin/cat").with_stdio_sockets().start().unwrap();
+        let before = running.io_counters().unwrap();
+        running.write_all(&[b'x'; 10000]).unwrap();
+        let mut buf = [0; 10000];
+        running.read_exact(&mut buf).unwrap();
+
+        let after = running.io_counters().unwrap();
+        assert!(after.rchar - before.rchar >= 10000);
+        assert!(after.wchar - before.wchar >= 10000);
+
+        running.terminate(None).unwrap();
+        assert!(running.io_counters().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn child_environ() {
+        let running = Runny::new("/bin/sleep 5").start().unwrap();
+        let environ = running.environ().unwrap();
+        assert_eq!(environ.get("PATH").cloned(), std::env::var("PATH").ok());
+        assert_eq!(running.environ_var("PATH").unwrap(), std::env::var("PATH").ok());
+        assert_eq!(running.environ_var("RUNNY_NOT_SET").unwrap(), None);
+        assert_eq!(running.environ_at_exec().unwrap(), environ);
+        assert_eq!(running.environ_at_exec_var("PATH").unwrap(), std::env::var("PATH").ok());
+
+        running.terminate(None).unwrap();
+        assert!(running.environ().is_err());
+        assert!(running.environ_var("PATH").is_err());
+        assert!(running.environ_at_exec_var("PATH").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn container_init_is_pid_1() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n $$'")
+            .with_container_init()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "1");
+        assert_eq!(running.result(), 0);
+
+        let running = Runny::new("/bin/sleep 1000").with_container_init().start().unwrap();
+        assert_eq!(running.child_exe_path().unwrap(), std::fs::canonicalize("/bin/sleep").unwrap());
+        let stat = String::from_utf8(std::fs::read(format!("/proc/{}/stat", running.pid())).unwrap()).unwrap();
+        assert!(stat.contains("(sleep)"));
+        running.terminate(None).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn container_init_terminate() {
+        // Creating a PID namespace needs CAP_SYS_ADMIN.
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        // The command gets SIGTERM and the grace period to act on it.
+        let mut running = Runny::new("/bin/bash -c 'trap \"sleep 0.5; exit 7\" TERM; echo ready; \
+                                      while :; do sleep 0.1; done'")
+            .with_container_init()
+            .start()
+            .unwrap();
+        let mut ready = [0; 6];
+        running.read_exact(&mut ready).unwrap();
+        let start = Instant::now();
+        assert_eq!(running.terminate(Some(Duration::from_secs(5))).unwrap(), 7);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        // Without a handler, it's killed as though it had the default action.
+        let running = Runny::new("/bin/sleep 1000").with_container_init().start().unwrap();
+        let start = Instant::now();
+        assert_eq!(running.terminate(Some(Duration::from_secs(5))).unwrap(), 128 + 15 /* SIGTERM */);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_group() {
+        let mut running = Runny::new("/bin/bash -c 'trap \"echo -n hup; exit 0\" HUP; echo \
+                                      ready; while true; do sleep 0.1; done'")
+            .timeout(Duration::from_secs(5))
+            .start()
+            .unwrap();
+        let mut output = io::BufReader::new(running.take_output());
+        let mut line = String::new();
+        output.read_line(&mut line).unwrap();
+        assert_eq!(line, "ready\n");
+
+        running.send_signal_group(nix::sys::signal::SIGHUP).unwrap();
+        let mut s = String::new();
+        output.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hup");
+        assert_eq!(running.result(), 0);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn fake_runny() {
+        let mut running = testing::FakeRunny::new("fake output", 3).start().unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        running.write_all(b"input").unwrap();
+
+        assert_eq!(s, "fake output");
+        assert_eq!(running.written(), b"input");
+        assert_eq!(running.terminate(None).unwrap(), 3);
+        assert_eq!(running.result(), 3);
+
+        // Code written against Process takes either.
+        fn echo_back<P: testing::Process>(process: &mut P) -> i32 {
+            let mut s = String::new();
+            process.read_to_string(&mut s).unwrap();
+            process.write_all(s.as_bytes()).unwrap();
+            process.wait().unwrap()
+        }
+        let mut fake = testing::FakeRunny::new("echo", 0).start().unwrap();
+        assert_eq!(echo_back(&mut fake), 0);
+        assert_eq!(fake.written(), b"echo");
+        let mut real = Runny::new("/bin/bash -c 'echo real; exit 4'").start().unwrap();
+        assert_eq!(echo_back(&mut real), 4);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn terminate_on_parent_exit() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+                let running = Runny::new("/bin/sleep 1000")
+                    .terminate_on_parent_exit(nix::sys::signal::SIGTERM)
+                    .start()
+                    .unwrap();
+                tx.send(running).unwrap();
+            })
+            .join()
+            .unwrap();
+
+        let start_time = Instant::now();
+        let running = rx.recv().unwrap();
+        assert_eq!(running.result(), -2);
+        assert!(Instant::now().duration_since(start_time) < Duration::from_secs(5));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stop_and_continue() {
+        use std::thread;
+
+        fn wait_for_state(running: &running::Running, stopped: bool) -> bool {
+            for _ in 0..50 {
+                if running.is_stopped() == stopped {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            false
+        }
+
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        assert!(!running.is_stopped());
+
+        running.send_signal_group(nix::sys::signal::SIGSTOP).unwrap();
+        assert!(wait_for_state(&running, true));
+
+        running.send_sigcont().unwrap();
+        assert!(wait_for_state(&running, false));
+
+        running.terminate(None).unwrap();
+        assert!(!running.is_stopped());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs CAP_SETGID"]
+    fn supplementary_groups() {
+        let mut running = Runny::new("/usr/bin/id -G")
+            .with_supplementary_groups(vec![1234, 5678])
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        let groups: Vec<&str> = s.split_whitespace().collect();
+        assert!(groups.contains(&"1234"));
+        assert!(groups.contains(&"5678"));
+
+        let mut runny = Runny::new("/usr/bin/id -G");
+        assert!(runny.with_groups_from_user("no-such-user-here").is_err());
+        assert_eq!(runny.with_groups_from_user("root").unwrap().start().unwrap().result(), 0);
+    }
+
+    #[cfg(all(unix, feature = "hashing"))]
+    #[test]
+    fn output_hash() {
+        let mut running = Runny::new("/bin/echo -n abc").start().unwrap();
+        let hash = running.output_hash().unwrap();
+        assert_eq!(hash[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert_eq!(hash[28..], [0xf2, 0x00, 0x15, 0xad]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fd_passing() {
+        let mut running = Runny::new("/bin/bash -c 'readlink /proc/$$/fd/$RUNNY_FD_SOCKET'")
+            .with_fd_passing()
+            .start()
+            .unwrap();
+        assert!(running.fd_passing_socket().is_some());
+
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert!(s.starts_with("socket:["));
+        assert!(Runny::new("/bin/true").start().unwrap().fd_passing_socket().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify_socket() {
+        // Notify on the child's behalf, rather than relying on systemd-notify
+        // being installed.
+        let mut running = Runny::new("/bin/bash -c 'echo $NOTIFY_SOCKET; sleep 1000'")
+            .with_notify_socket()
+            .start()
+            .unwrap();
+        let mut path = String::new();
+        io::BufReader::new(running.take_output()).read_line(&mut path).unwrap();
+        let notifier = UnixDatagram::unbound().unwrap();
+        notifier.send_to(b"STATUS=started", path.trim()).unwrap();
+        notifier.send_to(b"READY=1", path.trim()).unwrap();
+        let vars = running.wait_for_notify(Duration::from_secs(5)).unwrap();
+        assert_eq!(vars.get("READY").map(|s| s.as_str()), Some("1"));
+        assert_eq!(vars.get("STATUS").map(|s| s.as_str()), Some("started"));
+        running.terminate(None).unwrap();
+
+        let running = Runny::new("/bin/true").with_notify_socket().start().unwrap();
+        match running.wait_for_notify(Duration::from_secs(5)) {
+            Err(running::NotifyError::ProcessExited(0)) => (),
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wait_for_idle() {
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        running.wait_for_idle(5.0, Duration::from_millis(200), Duration::from_secs(5)).unwrap();
+        running.terminate(None).unwrap();
+
+        let running = Runny::new("/bin/bash -c 'while :; do :; done'").start().unwrap();
+        match running.wait_for_idle(20.0, Duration::from_millis(200), Duration::from_millis(500)) {
+            Err(running::WaitError::Timeout) => (),
+            e => panic!("unexpected result: {:?}", e),
+        }
+        assert!(running.cpu_time().unwrap() >= Duration::from_millis(100));
+        running.terminate(None).unwrap();
+
+        let running = Runny::new("/bin/bash -c 'exit 2'").start().unwrap();
+        match running.wait_for_idle(5.0, Duration::from_secs(10), Duration::from_secs(5)) {
+            Err(running::WaitError::ProcessExited(2)) => (),
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_for_port() {
+        use std::net::{TcpListener, TcpStream};
+
+        // Find a free port, and have the child listen on it after a second.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let start = Instant::now();
+        let running = Runny::new(&format!("/usr/bin/perl -MIO::Socket::INET -e 'sleep 1; \
+                                           $s = IO::Socket::INET->new(LocalPort => {}, Listen => 1) or die; \
+                                           sleep 1000'",
+                                          port))
+            .start()
+            .unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        running.wait_for_port(port, Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+        running.terminate(None).unwrap();
+
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        match running.wait_for_port(port, Duration::from_secs(1)) {
+            Err(running::WaitError::Timeout) => (),
+            e => panic!("unexpected result: {:?}", e),
+        }
+        running.terminate(None).unwrap();
+
+        let running = Runny::new("/bin/bash -c 'exit 2'").start().unwrap();
+        match running.wait_for_port(port, Duration::from_secs(5)) {
+            Err(running::WaitError::ProcessExited(2)) => (),
+            e => panic!("unexpected result: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn getters() {
+        let mut runny = Runny::new("/bin/echo hi");
+        assert_eq!(runny.command(), "/bin/echo hi");
+        assert_eq!(runny.working_directory(), None);
+        assert_eq!(runny.get_timeout(), None);
+        assert!(runny.get_path().is_empty());
+
+        runny.directory(&Some(PathBuf::from("/tmp")))
+            .timeout(Duration::from_secs(3))
+            .path(vec![PathBuf::from("/bin")]);
+        assert_eq!(runny.working_directory(), Some(Path::new("/tmp")));
+        assert_eq!(runny.get_timeout(), Some(Duration::from_secs(3)));
+        assert_eq!(runny.get_path(), &[PathBuf::from("/bin")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn elapsed_wall_time() {
+        let running = Runny::new("/bin/sleep 1").start().unwrap();
+        assert!(running.elapsed_wall_time() < Duration::from_secs(1));
+        running.wait().unwrap();
+        let elapsed = running.elapsed_wall_time();
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(3));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(running.elapsed_wall_time(), elapsed);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn graceful_shutdown_delay() {
+        let mut running = Runny::new("/bin/bash -c 'trap \"sleep 1; echo -n Done; exit 3\" TERM; \
+                                      echo -n Ready; while true; do sleep 0.1; done'")
+            .with_graceful_shutdown_delay(Duration::from_secs(5))
+            .start()
+            .unwrap();
+        let mut output = running.take_output();
+        let mut buf = [0; 5];
+        output.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Ready");
+
+        assert_eq!(running.terminate(None).unwrap(), 3);
+        let mut s = String::new();
+        output.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Done");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_rate() {
+        let mut running = Runny::new("/usr/bin/seq 1 1000").start().unwrap();
+        assert_eq!(running.total_output_bytes(), 0);
+        let mut output = running.take_output();
+        let mut s = String::new();
+        output.read_to_string(&mut s).unwrap();
+        assert_eq!(running.total_output_bytes(), s.len() as u64);
+        assert_eq!(running.output_rate_bytes_per_sec(), s.len() as f64);
+
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(running.output_rate_bytes_per_sec(), 0.0);
+        assert_eq!(running.total_output_bytes(), s.len() as u64);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn default_path() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n $PATH'")
+            .path(vec![PathBuf::from("/opt/runny")])
+            .with_default_path()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "/opt/runny:/usr/local/bin:/usr/bin:/bin");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn coredump_enabled() {
+        let mut running = Runny::new("/bin/bash -c 'ulimit -c'").with_coredump_enabled(false).start().unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.trim(), "0");
+
+        let mut running = Runny::new("/bin/bash -c 'ulimit -c; ulimit -Hc'").with_coredump_enabled(true).start().unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        let limits: Vec<&str> = s.split_whitespace().collect();
+        assert_eq!(limits[0], limits[1]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("runny-umask-{}", std::process::id()));
+        let cmd = format!("/usr/bin/touch {}", path.display());
+        assert_eq!(Runny::new(&cmd).with_umask(0o077).start().unwrap().result(), 0);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(Runny::new("/bin/true").with_umask(0o10000).start().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_name() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n $0'")
+            .with_process_name("runny-test")
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "runny-test");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_on_drop() {
+        use nix::sys::signal::kill;
+        use nix::unistd::Pid;
+
+        let mut running = Runny::new("/bin/sleep 1000").start().unwrap();
+        running.kill_on_drop(false);
+        let pid = Pid::from_raw(running.pid());
+        drop(running);
+        thread::sleep(Duration::from_millis(500));
+        assert!(kill(pid, None).is_ok());
+        kill(pid, nix::sys::signal::SIGKILL).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_timeout_or_kill() {
+        let running = Runny::new("/bin/bash -c 'exit 4'").start().unwrap();
+        assert_eq!(running.wait_timeout_or_kill(Duration::from_secs(5)).unwrap(), 4);
+
+        let start_time = Instant::now();
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        running.wait_timeout_or_kill(Duration::from_secs(1)).unwrap();
+        let elapsed = Instant::now().duration_since(start_time);
+        assert!(elapsed >= Duration::from_secs(1));
+        assert!(elapsed < Duration::from_secs(3));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_secrets() {
+        let mut secrets = HashMap::new();
+        secrets.insert("RUNNY_SECRET".to_owned(), "hunter2");
+        // Secrets take the place of variables the child would inherit.
+        secrets.insert("HOME".to_owned(), "/secret-home");
+        let mut cmd = Runny::new("/bin/bash -c 'echo -n $RUNNY_SECRET $HOME'");
+        cmd.env_secrets(secrets);
+        assert!(!format!("{:?}", cmd).contains("hunter2"));
+        assert_eq!(SecretString::from("hunter2".to_owned()).to_string(), "[redacted]");
+
+        let mut s = String::new();
+        cmd.start().unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hunter2 /secret-home");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_size_from_env() {
+        set_test_env("COLUMNS", Some("100"));
+        set_test_env("LINES", Some("many"));
+        let mut running = Runny::new("/bin/stty size").with_pty_size_from_env(80, 24).start().unwrap();
+
+        let mut s = String::new();
         running.read_to_string(&mut s).unwrap();
-        let end_time = Instant::now();
+        assert_eq!(s.trim(), "24 100");
+    }
 
-        // Give one extra second for timeout, to account for plumbing.
-        assert!(end_time.duration_since(start_time) < Duration::from_secs(timeout_secs + 1));
-        assert!(end_time.duration_since(start_time) > Duration::from_secs(timeout_secs - 1));
-        assert_eq!(s, "Hi there");
+    #[cfg(unix)]
+    #[test]
+    fn ready_timeout() {
+        let mut running = Runny::new("/bin/bash -c 'sleep 0.5; echo hi; echo err >&2'").start().unwrap();
+        assert!(!running.stdout_ready_timeout(Duration::from_millis(50)));
+        assert!(running.stdout_ready_timeout(Duration::from_secs(5)));
+        assert!(running.stderr_ready_timeout(Duration::from_secs(5)));
+        running.take_output();
+        assert!(!running.stdout_ready_timeout(Duration::from_millis(0)));
+        running.wait().unwrap();
     }
 
     #[cfg(unix)]
     #[test]
-    fn read_write() {
-        let mut running = Runny::new("/bin/bash -c 'echo Input:; read foo; echo Got string: \
-                                  -$foo-; sleep 1; echo End'")
+    fn bytes_available() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n hello; echo -n oops >&2'").start().unwrap();
+        running.wait().unwrap();
+        assert_eq!(running.output_bytes_available().unwrap(), 5);
+        assert_eq!(running.error_bytes_available().unwrap(), 4);
+
+        let mut s = String::new();
+        running.take_error().read_to_string(&mut s).unwrap();
+        assert_eq!(running.output_bytes_available().unwrap(), 5);
+        assert!(running.error_bytes_available().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_winch() {
+        let mut running = Runny::new("/bin/sh -c 'trap \"stty size; exit\" WINCH; echo ready; \
+                                      while :; do sleep 0.1; done'")
+            .timeout(Duration::from_secs(5))
             .start()
             .unwrap();
-        let mut input = running.take_input();
-        let mut output = running.take_output();
-        writeln!(input, "bar").unwrap();
+        let mut output = io::BufReader::new(running.take_output());
+        let mut s = String::new();
+        output.read_line(&mut s).unwrap();
+        assert_eq!(s.trim(), "ready");
 
-        let mut result = String::new();
-        output.read_to_string(&mut result).unwrap();
+        running.send_winch(40, 100).unwrap();
+        s.clear();
+        output.read_to_string(&mut s).unwrap();
+        assert_eq!(s.trim(), "40 100");
+    }
 
+    #[cfg(unix)]
+    #[test]
+    fn send_key() {
+        use running::Key;
+
+        let mut running = Runny::new("/bin/sh -c 'head -c 8 | od -An -tx1'")
+            .timeout(Duration::from_secs(5))
+            .start()
+            .unwrap();
+        running.send_key(Key::Up).unwrap();
+        running.send_key(Key::F5).unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.split_whitespace().collect::<Vec<_>>(),
+                   ["1b", "5b", "41", "1b", "5b", "31", "35", "7e"]);
+
+        // There's no handle on the pty besides the input and output, so
+        // dropping those hangs up the terminal and cat sees EOF.
+        let mut running = Runny::new("/bin/cat").timeout(Duration::from_secs(5)).start().unwrap();
+        drop(running.take_input());
+        assert_eq!(running.send_key(Key::Enter).unwrap_err().raw_os_error(), Some(9 /* EBADF */));
+        drop(running.take_output());
+        assert_eq!(running.result(), 0);
+
+        let running = Runny::new("/bin/cat").with_stdio_sockets().start().unwrap();
+        assert_eq!(running.send_key(Key::Enter).unwrap_err().raw_os_error(), Some(25 /* ENOTTY */));
         running.terminate(None).unwrap();
-        assert_eq!(result, "Input:\nGot string: -bar-\nEnd\n");
     }
 
     #[cfg(unix)]
     #[test]
-    fn read_write_err() {
-        let mut running = Runny::new("/bin/bash -c 'echo Input:; read foo; echo Got string: \
-                                  -$foo-; echo -n Error string 1>&2; sleep 1; echo Cool'")
+    fn stdio_from_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let path = env::temp_dir().join(format!("runny-stdio-{}", std::process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo Got $foo; echo oops >&2'")
+            .with_stdio_from_unix_socket(&path)
+            .start()
+            .unwrap();
+        let (mut socket, _) = listener.accept().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(running.has_socket_stdio());
+
+        socket.write_all(b"bar\n").unwrap();
+        let mut s = String::new();
+        socket.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Got bar\noops\n");
+        assert_eq!(running.result(), 0);
+
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "");
+        assert!(!Runny::new("/bin/true").start().unwrap().has_socket_stdio());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_pts() {
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'").start().unwrap();
+        let mut pts = running.open_pts().unwrap();
+        assert!(nix::unistd::isatty(pts.as_raw_fd()).unwrap());
+
+        // Output written to the slave side reaches the master just as the
+        // child's own does.  It has to be closed before the master sees EOF.
+        pts.write_all(b"Hi ").unwrap();
+        drop(pts);
+        running.write_all(b"bar\n").unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Hi Got bar");
+
+        let running = Runny::new("/bin/true").with_stdio_sockets().start().unwrap();
+        assert!(running.open_pts().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn socket_activation() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let inode = std::fs::read_link(format!("/proc/self/fd/{}", listener.as_raw_fd())).unwrap();
+        let mut running = Runny::new("/bin/bash -c 'echo $LISTEN_FDS $LISTEN_PID $$ $RUNNY_TEST; \
+                                      readlink /proc/$$/fd/3'")
+            .with_socket_activation(vec![listener.as_raw_fd()])
+            .env_secrets(vec![("RUNNY_TEST".to_owned(), "kept")].into_iter().collect())
+            .start()
+            .unwrap();
+
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        let mut lines = s.lines();
+        let fields: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+        assert_eq!(fields[0], "1");
+        assert_eq!(fields[1], fields[2]);
+        assert_eq!(fields[3], "kept");
+        assert_eq!(lines.next().unwrap(), inode.to_str().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_script() {
+        let mut running = Runny::new("/bin/bash -c 'read a; read b; echo -n $a$b'").start().unwrap();
+        running.set_script_delay(Duration::from_millis(10));
+        running.write_script(&["foo", "bar"]).unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "foobar");
+
+        let mut running = Runny::new("/bin/bash -c 'echo -n \"> \"; \
+                                      while read l; do echo \"got $l\"; echo -n \"> \"; done'")
             .timeout(Duration::from_secs(5))
             .start()
             .unwrap();
-        let mut input = running.take_input();
+        let responses = running.write_script_and_wait_for(&["foo", "bar"], "> ", Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(responses, ["got foo\n", "got bar\n"]);
+        let err = running.write_script_and_wait_for(&[], "$ ", Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stdio_sockets() {
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'")
+            .with_stdio_sockets()
+            .start()
+            .unwrap();
+        let mut io = running.take_io().unwrap();
+        assert!(running.take_io().is_err());
+        writeln!(io, "bar").unwrap();
+        let mut s = String::new();
+        io.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Got bar");
+        assert_eq!(running.result(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_exact_timeout() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n abc; sleep 1000'").start().unwrap();
         let mut output = running.take_output();
-        let mut error = running.take_error();
+        let mut buf = [0; 3];
+        output.read_exact_timeout(&mut buf, Duration::from_secs(5)).unwrap();
+        assert_eq!(&buf, b"abc");
 
-        writeln!(input, "bar").unwrap();
+        let start = Instant::now();
+        let err = output.read_exact_timeout(&mut buf, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
 
-        let mut result = String::new();
-        let mut err_result = String::new();
+    #[cfg(unix)]
+    #[test]
+    fn stdio_timeout() {
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'")
+            .with_stdio_timeout(Duration::from_millis(500), Duration::from_millis(500))
+            .start()
+            .unwrap();
+        let mut buf = [0; 16];
+        let err = running.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        writeln!(running, "bar").unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Got bar");
 
-        output.read_to_string(&mut result).unwrap();
-        error.read_to_string(&mut err_result).unwrap();
+        let mut running = Runny::new("/bin/bash -c 'read foo; echo -n Got $foo'")
+            .with_stdio_sockets()
+            .with_stdio_timeout(Duration::from_millis(500), Duration::from_millis(500))
+            .start()
+            .unwrap();
+        let mut io = running.take_io().unwrap();
+        let err = io.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        writeln!(io, "bar").unwrap();
+        let mut s = String::new();
+        io.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "Got bar");
 
-        running.terminate(None).unwrap();
-        println!("stdout: {}", result);
-        println!("stderr: {}", err_result);
-        assert_eq!(result, "Input:\nGot string: -bar-\nCool\n");
-        assert_eq!(err_result, "Error string");
+        // Threads that copy the output keep going through quiet spells.
+        let mut running = Runny::new("/bin/bash -c 'sleep 0.3; echo -n one; sleep 0.3; echo -n two'")
+            .with_stdio_timeout(Duration::from_millis(100), Duration::from_millis(100))
+            .start()
+            .unwrap();
+        let output = Arc::new(Mutex::new(vec![]));
+        let output_thr = output.clone();
+        running.watch_output(move |buf| output_thr.lock().unwrap().extend_from_slice(buf))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(*output.lock().unwrap(), b"onetwo");
     }
 
     #[cfg(unix)]
     #[test]
-    fn exit_codes() {
-        assert_eq!(Runny::new("/bin/true").start().unwrap().result(), 0);
-        assert_ne!(Runny::new("/bin/false").start().unwrap().result(), 0);
+    fn forward_signals() {
+        use nix::sys::signal::{kill, SIGUSR2};
+        use nix::unistd::getpid;
+
+        let mut running = Runny::new("/bin/bash -c 'trap \"echo -n got; exit 0\" USR2; \
+                                      echo -n ready; while true; do sleep 0.1; done'")
+            .forward_signals(&[SIGUSR2])
+            .start()
+            .unwrap();
+        let mut buf = [0; 5];
+        running.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ready");
+
+        kill(getpid(), SIGUSR2).unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "got");
+        assert_eq!(running.result(), 0);
+
+        assert!(Runny::new("/bin/true").forward_signals(&[nix::sys::signal::SIGKILL]).start().is_err());
     }
 
     #[cfg(unix)]
     #[test]
-    fn running_waiter_wait() {
-        let cmd = Runny::new("/bin/bash -c 'sleep 2'");
+    fn freeze_thaw() {
+        let mut running = Runny::new("/bin/bash -c 'while true; do echo -n x; sleep 0.1; done'")
+            .start()
+            .unwrap();
+        let mut output = running.take_output();
+        thread::spawn(move || io::copy(&mut output, &mut io::sink()));
+        thread::sleep(Duration::from_millis(300));
+        running.freeze().unwrap();
+        running.freeze().unwrap();
+        thread::sleep(Duration::from_millis(200));
 
-        let start_time = Instant::now();
-        let run = cmd.start().unwrap();
-        let waiter = run.waiter();
-        waiter.wait();
-        let end_time = Instant::now();
+        let frozen_bytes = running.total_output_bytes();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(running.total_output_bytes(), frozen_bytes);
 
-        // Give one extra second for timeout, to account for plumbing.
-        assert!(end_time.duration_since(start_time) < Duration::from_secs(3));
-        assert!(end_time.duration_since(start_time) > Duration::from_secs(1));
+        running.thaw().unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(running.total_output_bytes() > frozen_bytes);
+        running.terminate(None).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn namespaces() {
+        let mut running = Runny::new("/bin/bash -c 'hostname runny-test && hostname && \
+                                      readlink /proc/self/ns/ipc'")
+            .with_ipc_namespace()
+            .with_uts_namespace()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(running.result(), 0);
+
+        let mut lines = s.lines();
+        assert_eq!(lines.next(), Some("runny-test"));
+        let our_ipc = std::fs::read_link("/proc/self/ns/ipc").unwrap();
+        assert_ne!(lines.next().map(PathBuf::from), Some(our_ipc));
+        assert_ne!(std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap().trim(),
+                   "runny-test");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn oom_score_adj() {
+        let mut running = Runny::new("/bin/cat /proc/self/oom_score_adj")
+            .with_oom_score_adj(500)
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.trim(), "500");
+
+        let mut running = Runny::new("/bin/cat /proc/self/oom_score_adj")
+            .with_oom_score_adj_max()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s.trim(), "1000");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sysfs_write() {
+        // Any file will do to see that the writes happen.
+        let first = env::temp_dir().join(format!("runny-sysfs-a-{}", std::process::id()));
+        let second = env::temp_dir().join(format!("runny-sysfs-b-{}", std::process::id()));
+        let running = Runny::new("/bin/true")
+            .with_sysfs_write(first.to_str().unwrap(), "one")
+            .with_sysfs_write(second.to_str().unwrap(), "two")
+            .start()
+            .unwrap();
+        assert_eq!(running.result(), 0);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "one");
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "two");
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+
+        assert!(Runny::new("/bin/true").with_sysfs_write("/sys/runny/no-such-knob", "1").start().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn scheduler() {
+        let running = Runny::new("/bin/sleep 1000")
+            .with_scheduler(SchedPolicy::Batch, 0)
+            .start()
+            .unwrap();
+        // The policy is field 41 of stat, counting from the pid, and the
+        // fields after the command name start with the third.
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", running.pid())).unwrap();
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 1..].split_whitespace().collect();
+        assert_eq!(fields[41 - 3].parse::<nix::libc::c_int>().unwrap(), nix::libc::SCHED_BATCH);
+        running.terminate(None).unwrap();
+
+        assert!(Runny::new("/bin/true").with_scheduler(SchedPolicy::Fifo, 100).start().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn network_disabled() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let cmd = format!("/bin/bash -c 'exec 3<>/dev/tcp/127.0.0.1/{}'",
+                          listener.local_addr().unwrap().port());
+
+        let mut running = Runny::new(&cmd).with_network_disabled(false).start().unwrap();
+        let mut s = String::new();
+        running.take_error().read_to_string(&mut s).unwrap();
+        assert_ne!(running.result(), 0);
+        assert!(s.contains("Network is unreachable"), "{}", s);
+
+        let mut running = Runny::new(&cmd).with_network_disabled(true).start().unwrap();
+        let mut s = String::new();
+        running.take_error().read_to_string(&mut s).unwrap();
+        assert_ne!(running.result(), 0);
+        assert!(s.contains("Connection refused"), "{}", s);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn tmpfs_at() {
+        let dir = env::temp_dir().join(format!("runny-tmpfs-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let cmd = format!("/bin/bash -c 'echo hi > {0}/file && stat -f -c %T {0} && \
+                           grep -o size=[0-9]*k /proc/self/mounts'",
+                          dir.display());
+        let mut running = Runny::new(&cmd)
+            .with_tmpfs_at(dir.to_str().unwrap(), 2)
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(running.result(), 0);
+        assert!(s.starts_with("tmpfs\n"));
+        assert!(s.contains("size=2048k"));
+        assert!(!dir.join("file").exists());
+        std::fs::remove_dir(&dir).unwrap();
     }
 
     #[cfg(unix)]
     #[test]
-    fn running_waiter_result() {
-        let run = Runny::new("/bin/bash -c 'exit 1'").start().unwrap();
-        let waiter = run.waiter();
-        waiter.wait();
-        assert_eq!(waiter.result(), 1);
+    fn try_terminate() {
+        let start_time = Instant::now();
+        let running = Runny::new("/bin/sleep 1000").start().unwrap();
+        assert!(running.try_terminate(Some(Duration::from_secs(1))).is_none());
+        assert!(Instant::now().duration_since(start_time) < Duration::from_millis(500));
+        running.wait().unwrap();
+        assert!(Instant::now().duration_since(start_time) < Duration::from_secs(5));
+        assert!(running.try_terminate(None).unwrap().is_ok());
     }
 
     #[cfg(unix)]
     #[test]
-    fn read_stderr() {
-        let mut run = Runny::new("/bin/bash -c 'echo -n error-test 1>&2'").start().unwrap();
-        let mut s = String::new();
-        let mut stderr = run.take_error();
-        stderr.read_to_string(&mut s).unwrap();
-        assert_eq!(s, "error-test");
+    #[ignore = "writes to the host's syslog"]
+    fn syslog() {
+        let mut running = Runny::new("/bin/bash -c 'echo -n err 1>&2; echo -n out'")
+            .with_syslog("runny-test", nix::libc::LOG_USER)
+            .start()
+            .unwrap();
+        let (output, error) = running.wait_all_output().unwrap();
+        assert_eq!(output, b"out");
+        assert_eq!(error, b"");
+        assert_eq!(running.result(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn syslog_bad_facility() {
+        assert!(Runny::new("/bin/true").with_syslog("runny-test", -1).start().is_err());
     }
 
     #[test]
@@ -415,6 +3964,108 @@ mod tests {
         }
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn from_argv() {
+        let mut running = Runny::from_argv(&["/bin/echo", "-n", "a  'b' $HOME"])
+            .unwrap()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "a  'b' $HOME");
+
+        let empty: &[&str] = &[];
+        assert!(Runny::from_argv(empty).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolved_path() {
+        let mut running = Runny::new("echo -n resolved")
+            .with_resolved_path()
+            .unwrap()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "resolved");
+
+        assert!(Runny::new("does-not-exist-anywhere").with_resolved_path().is_err());
+    }
+
+    #[test]
+    fn env_expand() {
+        set_test_env("RUNNY_TEST_EXPAND", Some("xyz"));
+        set_test_env("RUNNY_TEST_UNSET", None);
+        assert_eq!(Runny::env_expand("a $RUNNY_TEST_EXPAND/b ${RUNNY_TEST_EXPAND}c"),
+                   "a xyz/b xyzc");
+        assert_eq!(Runny::env_expand("[$RUNNY_TEST_UNSET] $ 5$"), "[] $ 5$");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_expansion() {
+        set_test_env("RUNNY_TEST_EXPANSION", Some("expanded"));
+        let mut running = Runny::new("/bin/echo -n $RUNNY_TEST_EXPANSION")
+            .with_env_expansion()
+            .start()
+            .unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "expanded");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn arg_env() {
+        // Backslashes are kept, the same as in the command string.
+        set_test_env("RUNNY_TEST_ARGS", Some("-n 'two words' a\\b"));
+        let mut running = Runny::new("/bin/echo").with_arg_env("RUNNY_TEST_ARGS").start().unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "two words a\\b");
+
+        set_test_env("RUNNY_TEST_ARGS_BAD", Some("'unterminated"));
+        match Runny::new("/bin/echo").with_arg_env("RUNNY_TEST_ARGS_BAD").start() {
+            Err(RunnyError::InvalidArgEnv(var)) => assert_eq!(var, "RUNNY_TEST_ARGS_BAD"),
+            other => panic!("expected InvalidArgEnv, got {:?}", other.map(|_| ())),
+        }
+
+        set_test_env("RUNNY_TEST_ARGS_UNSET", None);
+        let mut running = Runny::new("/bin/echo -n plain").with_arg_env("RUNNY_TEST_ARGS_UNSET").start().unwrap();
+        let mut s = String::new();
+        running.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "plain");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn working_directory_from_env() {
+        set_test_env("RUNNY_TEST_WD", Some("/usr"));
+        set_test_env("RUNNY_TEST_WD_UNSET", None);
+
+        let mut s = String::new();
+        Runny::new("/bin/pwd")
+            .directory(&Some(PathBuf::from("/")))
+            .with_working_directory_from_env("RUNNY_TEST_WD")
+            .start()
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(s.trim(), "/usr");
+
+        let mut s = String::new();
+        Runny::new("/bin/pwd")
+            .directory(&Some(PathBuf::from("/")))
+            .with_working_directory_from_env("RUNNY_TEST_WD_UNSET")
+            .start()
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(s.trim(), "/");
+    }
+
     #[test]
     fn invalid_command() {
         let runny = Runny::new("/bin/does/not/exist");
@@ -4,33 +4,899 @@ extern crate user32;
 extern crate winapi;
 
 #[cfg(unix)]
-use self::nix::sys::signal::{kill, SIGTERM, SIGKILL};
+use self::nix::sys::signal::{kill, Signal, SIGCONT, SIGSTOP, SIGTERM, SIGKILL, SIGWINCH};
+#[cfg(unix)]
+use self::nix::sys::signal::{sigaction, SigAction, SigHandler, SigSet, SA_RESTART};
 
 #[cfg(unix)]
 use self::nix::unistd::Pid;
 
-use std::process::Child;
-use std::io::{self, Read, Result, Write};
-use std::fs::File;
+use std::process::{Child, ExitStatus};
+use std::io::{self, BufRead, BufReader, Read, Result, Write};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::fmt;
+use std::net::{SocketAddr, TcpStream};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::result;
 use std::sync::{Arc, Mutex, Condvar};
-use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{HashMap, VecDeque};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+#[cfg(unix)]
+use std::fs;
 
 pub struct RunningWaiter {
     result: Arc<(Mutex<Option<i32>>, Condvar)>,
     term_thr: Arc<Mutex<JoinHandle<()>>>,
     term_delay: Arc<Mutex<Option<Duration>>>,
+    default_term_delay: Option<Duration>,
+    term_requested: Arc<AtomicBool>,
 }
 
 pub struct RunningOutput {
     stream: File,
+    tee: Option<File>,
+    meter: Option<Arc<Mutex<OutputMeter>>>,
+    line_ending: Option<LineEndingTranslator>,
+    /// Translated output that didn't fit in the caller's buffer.
+    pending: Vec<u8>,
+    #[cfg(unix)]
+    timeout: Option<Duration>,
+}
+
+/// Wait for `fd` to become ready for `events`, failing with EAGAIN if it
+/// doesn't within `timeout`, the same as a socket with SO_RCVTIMEO set.
+#[cfg(unix)]
+fn wait_ready(fd: RawFd, events: self::nix::poll::EventFlags, timeout: Duration) -> Result<()> {
+    use self::nix::poll::{poll, PollFd};
+
+    let ms = timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32;
+    let mut fds = [PollFd::new(fd, events)];
+    loop {
+        match poll(&mut fds, ms) {
+            Ok(0) => return Err(io::Error::from_raw_os_error(self::nix::libc::EAGAIN)),
+            Ok(_) => return Ok(()),
+            Err(self::nix::Error::Sys(self::nix::Errno::EINTR)) => continue,
+            Err(e) => return Err(io::Error::other(e)),
+        }
+    }
+}
+
+/// Reads a stream to the end whatever read timeout was set on it with
+/// Runny::with_stdio_timeout(), for the threads that copy a whole stream
+/// elsewhere and would otherwise give up after the first quiet spell.
+struct UntilEof(RunningOutput);
+
+impl Read for UntilEof {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Counts bytes as they're read, remembering recent reads to work out a rate.
+#[derive(Default)]
+struct OutputMeter {
+    total: u64,
+    recent: VecDeque<(Instant, usize)>,
+}
+
+impl OutputMeter {
+    fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.total += bytes as u64;
+        self.recent.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(t, _)) = self.recent.front() {
+            if now.duration_since(t) < Duration::from_secs(1) {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// Children that signals are being forwarded to.  Each slot holds a pid in
+/// its upper 32 bits and a mask of signal numbers in its lower 32 bits, so
+/// that the signal handler can read it with a single atomic load.
+#[cfg(unix)]
+static FORWARD_SLOTS: [AtomicU64; 32] = [const { AtomicU64::new(0) }; 32];
+
+/// The handlers that forwarding replaced, along with how many children each
+/// signal is currently being forwarded to.
+#[cfg(unix)]
+static FORWARD_HANDLERS: Mutex<Vec<(Signal, usize, SigAction)>> = Mutex::new(Vec::new());
+
+#[cfg(unix)]
+extern "C" fn forward_signal(sig: self::nix::libc::c_int) {
+    #[cfg(target_os = "linux")]
+    let saved_errno = self::nix::errno::errno();
+    for slot in FORWARD_SLOTS.iter() {
+        let slot = slot.load(Ordering::SeqCst);
+        if slot >> 32 != 0 && slot & (1 << sig) != 0 {
+            unsafe { self::nix::libc::kill((slot >> 32) as self::nix::libc::pid_t, sig) };
+        }
+    }
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *self::nix::libc::__errno_location() = saved_errno;
+    }
+}
+
+/// Sends signals received by this process on to a child until it is dropped,
+/// at which point the previous handlers are put back once no other child
+/// wants those signals.
+#[cfg(unix)]
+struct SignalForwarder {
+    slot: usize,
+    signals: Vec<Signal>,
+}
+
+#[cfg(unix)]
+impl SignalForwarder {
+    fn new(pid: i32, signals: &[Signal]) -> Result<SignalForwarder> {
+        let mask = signals.iter().fold(0u64, |mask, &sig| mask | 1 << sig as u64);
+        let value = (pid as u64) << 32 | mask;
+        let slot = FORWARD_SLOTS.iter()
+            .position(|slot| slot.compare_exchange(0, value, Ordering::SeqCst, Ordering::SeqCst).is_ok())
+            .ok_or_else(|| io::Error::other("too many processes are having signals forwarded"))?;
+
+        let mut forwarder = SignalForwarder {
+            slot,
+            signals: vec![],
+        };
+        let mut handlers = FORWARD_HANDLERS.lock().unwrap();
+        for &sig in signals {
+            if forwarder.signals.contains(&sig) {
+                continue;
+            }
+            match handlers.iter_mut().find(|&&mut (s, _, _)| s == sig) {
+                Some(&mut (_, ref mut count, _)) => *count += 1,
+                None => {
+                    let action = SigAction::new(SigHandler::Handler(forward_signal),
+                                                SA_RESTART,
+                                                SigSet::empty());
+                    let old = unsafe { sigaction(sig, &action) }.map_err(io::Error::other)?;
+                    handlers.push((sig, 1, old));
+                }
+            }
+            forwarder.signals.push(sig);
+        }
+        Ok(forwarder)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SignalForwarder {
+    fn drop(&mut self) {
+        FORWARD_SLOTS[self.slot].store(0, Ordering::SeqCst);
+        let mut handlers = FORWARD_HANDLERS.lock().unwrap();
+        for &sig in &self.signals {
+            if let Some(i) = handlers.iter().position(|&(s, _, _)| s == sig) {
+                handlers[i].1 -= 1;
+                if handlers[i].1 == 0 {
+                    let (sig, _, old) = handlers.remove(i);
+                    unsafe { sigaction(sig, &old) }.ok();
+                }
+            }
+        }
+    }
+}
+
+/// The most recent lines of output, as kept by Running::buffer_output_lines().
+struct LineBuffer {
+    lines: VecDeque<String>,
+    capacity: usize,
+    /// How many lines have been read in all, including those since dropped.
+    total: u64,
+}
+
+impl LineBuffer {
+    fn push(&mut self, line: String) {
+        self.lines.push_back(line);
+        if self.lines.len() > self.capacity {
+            self.lines.pop_front();
+        }
+        self.total += 1;
+    }
+}
+
+/// How many lines output_lines_since() keeps if buffer_output_lines() wasn't
+/// called first.
+const DEFAULT_LINE_BUFFER: usize = 1000;
+
+/// The start of struct perf_event_attr, as of PERF_ATTR_SIZE_VER0, which is
+/// all that's needed for simple counting.
+#[cfg(all(target_os = "linux", feature = "perf"))]
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    bp_addr: u64,
+}
+
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_TYPE_HARDWARE: u32 = 0;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_TYPE_SOFTWARE: u32 = 1;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FLAG_DISABLED: u64 = 1 << 0;
+/// Also count threads and processes the process goes on to create.
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FLAG_INHERIT: u64 = 1 << 1;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FLAG_ENABLE_ON_EXEC: u64 = 1 << 12;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+const PERF_FLAG_FD_CLOEXEC: u64 = 1 << 3;
+
+#[cfg(all(target_os = "linux", feature = "perf"))]
+fn perf_event_open(attr: &PerfEventAttr, pid: i32) -> Result<RawFd> {
+    use self::nix::libc;
+
+    let fd = unsafe {
+        libc::syscall(libc::SYS_perf_event_open, attr as *const PerfEventAttr,
+                      pid, -1 as libc::c_int, -1 as libc::c_int, PERF_FLAG_FD_CLOEXEC)
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd as RawFd)
+}
+
+/// Performance counters that may be given to Runny::with_perf_events().
+#[cfg(all(target_os = "linux", feature = "perf"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PerfEvent {
+    CpuCycles,
+    Instructions,
+    CacheReferences,
+    CacheMisses,
+    BranchInstructions,
+    BranchMisses,
+    /// CPU time in nanoseconds.  This and the rest are counted by the kernel,
+    /// and so work even where there are no hardware counters.
+    TaskClock,
+    PageFaults,
+    ContextSwitches,
+}
+
+#[cfg(all(target_os = "linux", feature = "perf"))]
+impl PerfEvent {
+    fn type_and_config(self) -> (u32, u64) {
+        match self {
+            PerfEvent::CpuCycles => (PERF_TYPE_HARDWARE, 0),
+            PerfEvent::Instructions => (PERF_TYPE_HARDWARE, 1),
+            PerfEvent::CacheReferences => (PERF_TYPE_HARDWARE, 2),
+            PerfEvent::CacheMisses => (PERF_TYPE_HARDWARE, 3),
+            PerfEvent::BranchInstructions => (PERF_TYPE_HARDWARE, 4),
+            PerfEvent::BranchMisses => (PERF_TYPE_HARDWARE, 5),
+            PerfEvent::TaskClock => (PERF_TYPE_SOFTWARE, 1),
+            PerfEvent::PageFaults => (PERF_TYPE_SOFTWARE, 2),
+            PerfEvent::ContextSwitches => (PERF_TYPE_SOFTWARE, 3),
+        }
+    }
+}
+
+/// Open counters for `events` on `pid`, to start counting once it calls
+/// exec().  Only what happens in user space is counted.
+#[cfg(all(target_os = "linux", feature = "perf"))]
+pub(crate) fn open_perf_events(events: &[PerfEvent], pid: i32) -> Result<Vec<File>> {
+    let mut fds = vec![];
+    for event in events {
+        let (type_, config) = event.type_and_config();
+        let attr = PerfEventAttr {
+            type_,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+            flags: PERF_FLAG_DISABLED | PERF_FLAG_INHERIT | PERF_FLAG_EXCLUDE_KERNEL |
+                   PERF_FLAG_EXCLUDE_HV | PERF_FLAG_ENABLE_ON_EXEC,
+            ..Default::default()
+        };
+        let fd = perf_event_open(&attr, pid)?;
+        fds.push(unsafe { File::from_raw_fd(fd) });
+    }
+    Ok(fds)
+}
+
+/// Open a perf counter on `pid` for every syscall entry tracepoint, to start
+/// counting once it calls exec().  This needs tracefs to be mounted and
+/// perf_event_paranoid to allow it.
+#[cfg(all(target_os = "linux", feature = "perf"))]
+pub(crate) fn open_syscall_counters(pid: i32) -> Result<Vec<(String, File)>> {
+    let events = ["/sys/kernel/tracing/events/syscalls",
+                  "/sys/kernel/debug/tracing/events/syscalls"]
+        .iter()
+        .find_map(|dir| fs::read_dir(dir).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no syscall tracepoints in tracefs"))?;
+
+    let mut counters = vec![];
+    for entry in events {
+        let entry = entry?;
+        let name = match entry.file_name().to_str().and_then(|n| n.strip_prefix("sys_enter_")) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let id = fs::read_to_string(entry.path().join("id"))?;
+        let attr = PerfEventAttr {
+            type_: PERF_TYPE_TRACEPOINT,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config: id.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            flags: PERF_FLAG_DISABLED | PERF_FLAG_INHERIT | PERF_FLAG_ENABLE_ON_EXEC,
+            ..Default::default()
+        };
+        let fd = perf_event_open(&attr, pid)?;
+        counters.push((name, unsafe { File::from_raw_fd(fd) }));
+    }
+    Ok(counters)
+}
+
+/// Stop `pid` with ptrace and have it open `path` (which ends in a NUL) with
+/// `flags`, dup2() that over `target`, and close the original, before letting
+/// it carry on.  The tracing is done by a short-lived process forked for the
+/// purpose, since otherwise the thread waiting for the child to exit would be
+/// told about its ptrace stops.  That process only makes plain syscalls on
+/// buffers allocated beforehand, as this one may have other threads.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "ptrace"))]
+fn reopen_fd_with_ptrace(pid: i32, path: &[u8], flags: i32, target: RawFd) -> Result<()> {
+    use self::nix::libc;
+    use std::mem;
+
+    const SYSCALL: [u8; 2] = [0x0f, 0x05];
+    const SYS_RESTART_SYSCALL: u64 = 219;
+    const ERESTARTSYS: i64 = -512;
+    const ERESTARTNOINTR: i64 = -513;
+    const ERESTARTNOHAND: i64 = -514;
+    const ERESTART_RESTARTBLOCK: i64 = -516;
+
+    /// Wait for the tracee to stop, returning the signal it stopped with.
+    fn wait_stop(pid: i32) -> Option<i32> {
+        let mut status = 0;
+        loop {
+            match unsafe { libc::waitpid(pid, &mut status, libc::__WALL) } {
+                -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => continue,
+                -1 => return None,
+                _ if libc::WIFSTOPPED(status) => return Some(status),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Run syscall `nr` in the tracee using the syscall instruction at `regs.rip`.
+    fn inject(pid: i32, saved: &libc::user_regs_struct, nr: u64, args: [u64; 3], pending: &mut i32) -> i64 {
+        let mut regs = *saved;
+        regs.rax = nr;
+        regs.rdi = args[0];
+        regs.rsi = args[1];
+        regs.rdx = args[2];
+        // Keep the kernel from treating this as a syscall to restart.
+        regs.orig_rax = u64::MAX;
+        if unsafe { libc::ptrace(libc::PTRACE_SETREGS, pid, 0, &regs) } == -1 {
+            return -(io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO) as i64);
+        }
+        loop {
+            unsafe { libc::ptrace(libc::PTRACE_SINGLESTEP, pid, 0, 0) };
+            match wait_stop(pid) {
+                Some(status) if libc::WSTOPSIG(status) == libc::SIGTRAP => break,
+                // Hand anything else on once we're done.
+                Some(status) => *pending = libc::WSTOPSIG(status),
+                None => return -(libc::ESRCH as i64),
+            }
+        }
+        if unsafe { libc::ptrace(libc::PTRACE_GETREGS, pid, 0, &mut regs) } == -1 {
+            return -(libc::EIO as i64);
+        }
+        regs.rax as i64
+    }
+
+    fn trace(pid: i32, path: &[u8], flags: i32, target: RawFd, mem_path: &[u8]) -> i32 {
+        let errno = || io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO);
+
+        if unsafe { libc::ptrace(libc::PTRACE_SEIZE, pid, 0, 0) } == -1 ||
+           unsafe { libc::ptrace(libc::PTRACE_INTERRUPT, pid, 0, 0) } == -1 {
+            return errno();
+        }
+        // A signal may arrive ahead of the interrupt, to be passed on later.
+        let mut pending = match wait_stop(pid) {
+            Some(status) if status >> 16 == libc::PTRACE_EVENT_STOP => 0,
+            Some(status) => libc::WSTOPSIG(status),
+            None => return libc::ESRCH,
+        };
+
+        let mut saved: libc::user_regs_struct = unsafe { mem::zeroed() };
+        if unsafe { libc::ptrace(libc::PTRACE_GETREGS, pid, 0, &mut saved) } == -1 {
+            return errno();
+        }
+        // If it was interrupted in a syscall, have it make that syscall again
+        // once it's let go, since the restart would otherwise be lost.
+        if saved.orig_rax as i64 >= 0 {
+            match saved.rax as i64 {
+                ERESTARTSYS | ERESTARTNOINTR | ERESTARTNOHAND => {
+                    saved.rip -= 2;
+                    saved.rax = saved.orig_rax;
+                }
+                ERESTART_RESTARTBLOCK => {
+                    saved.rip -= 2;
+                    saved.rax = SYS_RESTART_SYSCALL;
+                }
+                _ => (),
+            }
+        }
+
+        let mem = unsafe { libc::open(mem_path.as_ptr() as *const libc::c_char, libc::O_RDWR | libc::O_CLOEXEC) };
+        if mem == -1 {
+            return errno();
+        }
+        // Put a syscall instruction where it's stopped, and the path below
+        // the red zone on its stack.
+        let mut original = [0u8; 2];
+        let path_addr = (saved.rsp - 128 - path.len() as u64) & !15;
+        let result = unsafe {
+            if libc::pread(mem, original.as_mut_ptr() as *mut libc::c_void, 2, saved.rip as libc::off_t) != 2 ||
+               libc::pwrite(mem, SYSCALL.as_ptr() as *const libc::c_void, 2, saved.rip as libc::off_t) != 2 ||
+               libc::pwrite(mem, path.as_ptr() as *const libc::c_void, path.len(), path_addr as libc::off_t) !=
+               path.len() as isize {
+                errno()
+            } else {
+                let fd = inject(pid, &saved, libc::SYS_open as u64, [path_addr, flags as u64, 0o666], &mut pending);
+                if fd < 0 {
+                    -fd as i32
+                } else {
+                    let ret = inject(pid, &saved, libc::SYS_dup2 as u64, [fd as u64, target as u64, 0], &mut pending);
+                    inject(pid, &saved, libc::SYS_close as u64, [fd as u64, 0, 0], &mut pending);
+                    if ret < 0 { -ret as i32 } else { 0 }
+                }
+            }
+        };
+        unsafe {
+            libc::pwrite(mem, original.as_ptr() as *const libc::c_void, 2, saved.rip as libc::off_t);
+            libc::close(mem);
+            libc::ptrace(libc::PTRACE_SETREGS, pid, 0, &saved);
+            libc::ptrace(libc::PTRACE_DETACH, pid, 0, pending);
+        }
+        result
+    }
+
+    let mem_path = format!("/proc/{}/mem\0", pid).into_bytes();
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe { libc::_exit(trace(pid, path, flags, target, &mem_path)) },
+        helper => {
+            let mut status = 0;
+            while unsafe { libc::waitpid(helper, &mut status, 0) } == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            match libc::WEXITSTATUS(status) {
+                0 if libc::WIFEXITED(status) => Ok(()),
+                0 => Err(io::Error::other("ptrace helper died")),
+                errno => Err(io::Error::from_raw_os_error(errno)),
+            }
+        }
+    }
+}
+
+/// Expand the core_pattern `pattern` for a core dumped by `pid` with `signal`
+/// at `time`, as the kernel would, adding ".pid" if `uses_pid` is set
+/// (core_uses_pid) and the pattern doesn't already have the pid.  This is
+/// None if the pattern uses something that isn't known here, or `comm` isn't.
+#[cfg(target_os = "linux")]
+pub(crate) fn expand_core_pattern(pattern: &str,
+                                  uses_pid: bool,
+                                  pid: i32,
+                                  signal: i32,
+                                  time: u64,
+                                  hostname: &str,
+                                  comm: Option<&str>)
+                                  -> Option<String> {
+    let mut path = String::new();
+    let mut has_pid = false;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => path.push('%'),
+            Some('p') | Some('P') | Some('i') | Some('I') => {
+                has_pid = true;
+                path.push_str(&pid.to_string());
+            }
+            Some('s') => path.push_str(&signal.to_string()),
+            Some('t') => path.push_str(&time.to_string()),
+            Some('h') => path.push_str(hostname),
+            Some('e') => path.push_str(comm?),
+            _ => return None,
+        }
+    }
+    if uses_pid && !has_pid {
+        path.push_str(&format!(".{}", pid));
+    }
+    Some(path)
+}
+
+/// The path to the file `name` in the cgroup v2 directory given in `cgroups`,
+/// the contents of /proc/{pid}/cgroup, under where `mountinfo` says cgroup2
+/// is mounted.
+#[cfg(target_os = "linux")]
+pub(crate) fn cgroup_v2_path(cgroups: &str, mountinfo: &str, name: &str) -> Result<PathBuf> {
+    find_cgroup_path(cgroups,
+                     mountinfo,
+                     |hierarchy| hierarchy.strip_prefix("0::"),
+                     |fs_type, _| fs_type == "cgroup2",
+                     name)
+}
+
+/// The path to the file `name` in the cgroup v1 directory for `controller`
+/// given in `cgroups`, under where `mountinfo` says that hierarchy is mounted.
+#[cfg(target_os = "linux")]
+pub(crate) fn cgroup_v1_path(cgroups: &str, mountinfo: &str, controller: &str, name: &str) -> Result<PathBuf> {
+    find_cgroup_path(cgroups,
+                     mountinfo,
+                     |hierarchy| {
+                         let (_, rest) = hierarchy.split_once(':')?;
+                         let (controllers, path) = rest.split_once(':')?;
+                         controllers.split(',').find(|c| *c == controller).map(|_| path)
+                     },
+                     |fs_type, options| fs_type == "cgroup" && options.split(',').any(|o| o == controller),
+                     name)
+}
+
+/// Find the cgroup from the first line of `cgroups` that `hierarchy` returns
+/// a path for, and look for it under the first mount in `mountinfo` whose
+/// filesystem type and options satisfy `mount`.
+#[cfg(target_os = "linux")]
+fn find_cgroup_path<H, M>(cgroups: &str, mountinfo: &str, hierarchy: H, mount: M, name: &str) -> Result<PathBuf>
+    where H: Fn(&str) -> Option<&str>,
+          M: Fn(&str, &str) -> bool
+{
+    let cgroup = cgroups.lines()
+        .find_map(&hierarchy)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in that cgroup hierarchy"))?;
+
+    // Each line of mountinfo has the mount point as its fifth field, and
+    // the filesystem type and its options after a " - " separator.
+    let mount_point = mountinfo.lines()
+        .find_map(|line| {
+            let (fields, fs_fields) = line.split_once(" - ")?;
+            let mut fs_fields = fs_fields.split_whitespace();
+            let fs_type = fs_fields.next()?;
+            let options = fs_fields.nth(1).unwrap_or("");
+            if mount(fs_type, options) {
+                fields.split_whitespace().nth(4)
+            } else {
+                None
+            }
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cgroup hierarchy isn't mounted"))?;
+    Ok(Path::new(mount_point).join(cgroup.trim_start_matches('/')).join(name))
+}
+
+/// Parse the contents of memory.max, where "max" means there's no limit.
+#[cfg(target_os = "linux")]
+pub(crate) fn parse_memory_limit(limit: &str) -> Result<u64> {
+    match limit.trim() {
+        "max" => Ok(u64::MAX),
+        limit => limit.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Line endings that input to or output from the child may be translated to,
+/// as set with Runny::with_stdin_line_ending() and with_stdout_line_ending().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    /// "\r\n" becomes "\n".
+    Unix,
+    /// A "\n" not already preceded by "\r" becomes "\r\n".
+    Windows,
+    /// Leave line endings alone.
+    Passthrough,
+}
+
+/// A key on a terminal keyboard, for Running::send_key().
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Delete,
+    Escape,
+    Enter,
+    Tab,
+}
+
+impl Key {
+    /// The bytes an xterm-compatible terminal sends for this key, with the
+    /// cursor keys in normal rather than application mode.
+    pub fn escape_sequence(&self) -> &'static [u8] {
+        match *self {
+            Key::Up => b"\x1b[A",
+            Key::Down => b"\x1b[B",
+            Key::Right => b"\x1b[C",
+            Key::Left => b"\x1b[D",
+            Key::F1 => b"\x1bOP",
+            Key::F2 => b"\x1bOQ",
+            Key::F3 => b"\x1bOR",
+            Key::F4 => b"\x1bOS",
+            Key::F5 => b"\x1b[15~",
+            Key::F6 => b"\x1b[17~",
+            Key::F7 => b"\x1b[18~",
+            Key::F8 => b"\x1b[19~",
+            Key::F9 => b"\x1b[20~",
+            Key::F10 => b"\x1b[21~",
+            Key::F11 => b"\x1b[23~",
+            Key::F12 => b"\x1b[24~",
+            Key::PageUp => b"\x1b[5~",
+            Key::PageDown => b"\x1b[6~",
+            Key::Home => b"\x1b[H",
+            Key::End => b"\x1b[F",
+            Key::Delete => b"\x1b[3~",
+            Key::Escape => b"\x1b",
+            Key::Enter => b"\r",
+            Key::Tab => b"\t",
+        }
+    }
+}
+
+/// Translates line endings in a stream that arrives a buffer at a time.
+struct LineEndingTranslator {
+    mode: LineEnding,
+    /// Whether the last byte seen was "\r".  In Unix mode it hasn't been
+    /// passed on yet, since it may turn out to be part of "\r\n".
+    after_cr: bool,
+}
+
+impl LineEndingTranslator {
+    fn new(mode: LineEnding) -> Option<LineEndingTranslator> {
+        match mode {
+            LineEnding::Passthrough => None,
+            mode => Some(LineEndingTranslator { mode, after_cr: false }),
+        }
+    }
+
+    fn translate(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buf.len());
+        for &b in buf {
+            match (self.mode, b) {
+                (LineEnding::Unix, b'\r') => {
+                    if self.after_cr {
+                        out.push(b'\r');
+                    }
+                }
+                (LineEnding::Unix, b) => {
+                    if self.after_cr && b != b'\n' {
+                        out.push(b'\r');
+                    }
+                    out.push(b);
+                }
+                (_, b'\n') if !self.after_cr => out.extend_from_slice(b"\r\n"),
+                (_, b) => out.push(b),
+            }
+            self.after_cr = b == b'\r';
+        }
+        out
+    }
+
+    /// Whatever is being held back at the end of the stream.
+    fn finish(&mut self) -> Vec<u8> {
+        let held = self.mode == LineEnding::Unix && self.after_cr;
+        self.after_cr = false;
+        if held { vec![b'\r'] } else { vec![] }
+    }
 }
 
+/// Transforms each buffer written to a `RunningInput` before it reaches the child.
+pub type InputFilter = Arc<Mutex<Box<dyn Fn(&[u8]) -> Vec<u8> + Send>>>;
+
 pub struct RunningInput {
     stream: File,
+    filter: Option<InputFilter>,
+    line_ending: Option<LineEndingTranslator>,
+    lines_written: Arc<AtomicU64>,
+    #[cfg(unix)]
+    timeout: Option<Duration>,
+}
+
+/// The parent's end of the socket used as both stdin and stdout when
+/// Runny::with_stdio_sockets() is used.
+#[cfg(unix)]
+pub struct RunningSocket {
+    stream: UnixStream,
+}
+
+/// stdout and stderr interleaved into a single stream, as with `2>&1`.
+pub struct CombinedOutput {
+    stream: io::PipeReader,
+}
+
+/// Everything a process wrote, along with how it exited.
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+}
+
+/// The first line where a process's output differed from what was expected,
+/// as found by Running::output_diff().  Lines keep their trailing newline, so
+/// that a missing newline shows up as a difference, and a line past the end
+/// of either output is empty.
+#[derive(Debug, PartialEq)]
+pub struct DiffResult {
+    /// The line number, starting from 1.
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// A TCP socket held open by the child, as returned by Running::netstat().
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetConnection {
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+    /// The connection state, such as "LISTEN" or "ESTABLISHED".
+    pub state: String,
+}
+
+/// The child's I/O totals, as returned by Running::io_counters().
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IoCounters {
+    /// Bytes passed to read() and similar calls, whether or not they came
+    /// from disk.
+    pub rchar: u64,
+    /// Bytes passed to write() and similar calls.
+    pub wchar: u64,
+    /// Bytes actually fetched from the storage layer.
+    pub read_bytes: u64,
+    /// Bytes sent to the storage layer.
+    pub write_bytes: u64,
+}
+
+/// Parse an "address:port" pair from /proc/net/tcp or /proc/net/tcp6, where
+/// the address is written as native-endian 32-bit words.
+#[cfg(target_os = "linux")]
+fn parse_proc_net_addr(s: &str) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let (addr, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = vec![];
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    match bytes.len() {
+        4 => Some(SocketAddr::new(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into(), port)),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_state_name(st: &str) -> String {
+    match st {
+        "01" => "ESTABLISHED",
+        "02" => "SYN_SENT",
+        "03" => "SYN_RECV",
+        "04" => "FIN_WAIT1",
+        "05" => "FIN_WAIT2",
+        "06" => "TIME_WAIT",
+        "07" => "CLOSE",
+        "08" => "CLOSE_WAIT",
+        "09" => "LAST_ACK",
+        "0A" => "LISTEN",
+        "0B" => "CLOSING",
+        "0C" => "NEW_SYN_RECV",
+        other => other,
+    }
+    .to_owned()
+}
+
+struct OutputCollector {
+    output: JoinHandle<Result<Vec<u8>>>,
+    error: JoinHandle<Result<Vec<u8>>>,
+}
+
+impl OutputCollector {
+    fn finish(self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let output = self.output
+            .join()
+            .map_err(|_| io::Error::other("stdout reader panicked"))??;
+        let error = self.error
+            .join()
+            .map_err(|_| io::Error::other("stderr reader panicked"))??;
+        Ok((output, error))
+    }
+}
+
+/// Discards everything written to it, keeping count of bytes, lines, and
+/// whitespace-separated words.
+#[derive(Default)]
+struct DevNull {
+    bytes: u64,
+    lines: u64,
+    words: u64,
+    in_word: bool,
+}
+
+impl Write for DevNull {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.bytes += buf.len() as u64;
+        for &b in buf {
+            if b == b'\n' {
+                self.lines += 1;
+            }
+            if b.is_ascii_whitespace() {
+                self.in_word = false;
+            } else if !self.in_word {
+                self.in_word = true;
+                self.words += 1;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(PartialEq)]
@@ -44,20 +910,112 @@ enum ProcessState {
 #[allow(dead_code)]
 pub struct Running {
     child_pid: i32,
+    /// The process running the command, which differs from child_pid when
+    /// a container init waits on it.
+    workload_pid: i32,
     input: Option<RunningInput>,
     output: Option<RunningOutput>,
     error: Option<RunningOutput>,
+    output_meter: Arc<Mutex<OutputMeter>>,
+    stdin_lines_written: Arc<AtomicU64>,
     term_thr: Arc<Mutex<JoinHandle<()>>>,
     term_delay: Arc<Mutex<Option<Duration>>>,
+    default_term_delay: Option<Duration>,
+    term_requested: Arc<AtomicBool>,
+    deadline: Arc<Mutex<Option<Instant>>>,
     wait_thr: JoinHandle<()>,
     result: Arc<(Mutex<Option<i32>>, Condvar)>,
+    exit_status: Arc<Mutex<Option<ExitStatus>>>,
+    #[cfg(unix)]
+    fd_socket: Option<UnixDatagram>,
+    #[cfg(unix)]
+    stdio_socket: Option<UnixStream>,
+    #[cfg(unix)]
+    pty: bool,
+    #[cfg(unix)]
+    socket_stdio: bool,
+    #[cfg(unix)]
+    notify_socket: Option<(UnixDatagram, PathBuf)>,
+    start_time: Instant,
+    exit_time: Arc<Mutex<Option<Instant>>>,
+    kill_on_drop: bool,
+    #[cfg(unix)]
+    frozen: AtomicBool,
+    #[cfg(unix)]
+    signal_forwarder: Option<SignalForwarder>,
+    attempt_exit_codes: Vec<i32>,
+    line_buffer: Option<Arc<Mutex<LineBuffer>>>,
+    script_delay: Duration,
+    /// The command line, for assertion messages.
+    command: String,
+    #[cfg(target_os = "linux")]
+    child_comm: Option<String>,
+    #[cfg(target_os = "linux")]
+    child_cwd: Option<PathBuf>,
     state: Arc<Mutex<ProcessState>>,
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    syscall_counters: Vec<(String, File)>,
+    /// Counters opened for Runny::with_perf_events(), in the order the
+    /// events were given, until set_perf_events() pairs them up.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    perf_files: Vec<File>,
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    perf_counters: Vec<(PerfEvent, File)>,
+}
+
+pub enum NotifyError {
+    NotifyIoError(io::Error),
+    NotConfigured,
+    TimedOut,
+    ProcessExited(i32),
+}
+
+impl From<io::Error> for NotifyError {
+    fn from(kind: io::Error) -> Self {
+        NotifyError::NotifyIoError(kind)
+    }
+}
+
+impl fmt::Debug for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotifyError::NotifyIoError(ref e) => write!(f, "Notify I/O error: {:?}", e),
+            NotifyError::NotConfigured => write!(f, "No notify socket was set up"),
+            NotifyError::TimedOut => write!(f, "Timed out waiting for READY=1"),
+            NotifyError::ProcessExited(c) => write!(f, "Process exited with {}", c),
+        }
+    }
+}
+
+pub enum WaitError {
+    WaitIoError(io::Error),
+    Timeout,
+    ProcessExited(i32),
+}
+
+impl From<io::Error> for WaitError {
+    fn from(kind: io::Error) -> Self {
+        WaitError::WaitIoError(kind)
+    }
+}
+
+impl fmt::Debug for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WaitError::WaitIoError(ref e) => write!(f, "Wait I/O error: {:?}", e),
+            WaitError::Timeout => write!(f, "Timed out"),
+            WaitError::ProcessExited(c) => write!(f, "Process exited with {}", c),
+        }
+    }
 }
 
 pub enum RunningError {
     RunningIoError(io::Error),
     #[cfg(unix)]
     RunningNixError(self::nix::Error),
+    /// The process didn't finish in time, and was killed.  This holds
+    /// whatever it wrote up until then.
+    TimedOut(Output),
 }
 
 impl From<io::Error> for RunningError {
@@ -79,6 +1037,7 @@ impl fmt::Debug for RunningError {
             &RunningError::RunningIoError(ref e) => write!(f, "Running I/O error: {:?}", e),
             #[cfg(unix)]
             &RunningError::RunningNixError(ref e) => write!(f, "Running Nix error: {:?}", e),
+            RunningError::TimedOut(o) => write!(f, "Timed out, with output {:?}", o),
         }
     }
 }
@@ -129,32 +1088,70 @@ impl Running {
         drop(child.stderr.take());
 
         let child_pid = child.id() as i32;
+        // Remember what core_dump_path() needs to know, before the child has
+        // a chance to be reaped.
+        #[cfg(target_os = "linux")]
+        let child_comm = fs::read_to_string(format!("/proc/{}/comm", child_pid))
+            .ok()
+            .map(|comm| comm.trim_end().to_owned());
+        #[cfg(target_os = "linux")]
+        let child_cwd = fs::read_link(format!("/proc/{}/cwd", child_pid)).ok();
         let child_result = Arc::new((Mutex::new(None), Condvar::new()));
         let child_result_thr = child_result.clone();
         let term_delay: Arc<Mutex<Option<Duration>>> = Arc::new(Mutex::new(None));
         let process_state = Arc::new(Mutex::new(ProcessState::Running));
+        let exit_status = Arc::new(Mutex::new(None));
+
+        let start_time = Instant::now();
+        let term_requested = Arc::new(AtomicBool::new(false));
+        let deadline = Arc::new(Mutex::new(timeout.map(|t| start_time + t)));
 
         let term_delay_thr = term_delay.clone();
+        let term_requested_thr = term_requested.clone();
+        let deadline_thr = deadline.clone();
+        let process_state_term_thr = process_state.clone();
 
         let term_thr = Arc::new(Mutex::new(thread::spawn(move || {
 
-            // Allow the child process to run for a given amount of time,
-            // or until we're woken up by a termination process.
-            if let Some(t) = timeout {
-                thread::park_timeout(t);
-            } else {
-                thread::park();
+            // Allow the child process to run until its deadline, or until
+            // we're woken up by a termination process.  set_timeout() may
+            // move the deadline, and wakes us up to take another look at it.
+            while !term_requested_thr.load(Ordering::SeqCst) {
+                let deadline = *deadline_thr.lock().unwrap();
+                match deadline {
+                    None => thread::park(),
+                    Some(d) => {
+                        let now = Instant::now();
+                        if now >= d {
+                            break;
+                        }
+                        thread::park_timeout(d - now);
+                    }
+                }
             }
 
+            // Wait out the grace period, unless the child exits before then.
+            let grace_period = move || {
+                let end = match *term_delay_thr.lock().unwrap() {
+                    Some(t) => Instant::now() + t,
+                    None => return,
+                };
+                while *process_state_term_thr.lock().unwrap() == ProcessState::Running {
+                    let now = Instant::now();
+                    if now >= end {
+                        break;
+                    }
+                    thread::park_timeout(end - now);
+                }
+            };
+
             // We've been woken up, so it's time to terminate the child process.
             // Use a negative value to terminate all children in the process group.
             #[cfg(unix)]
             {
                 kill(Pid::from_raw(-child_pid), SIGTERM).ok();
 
-                if let Some(t) = *term_delay_thr.lock().unwrap() {
-                    thread::park_timeout(t);
-                }
+                grace_period();
 
                 // Send a SIGKILL to all children, to ensure they're gone.
                 kill(Pid::from_raw(-child_pid), SIGKILL).ok();
@@ -164,9 +1161,7 @@ impl Running {
                 // Post the WM_CLOSE message to each window
                 send_wmclose(child_pid as self::winapi::LPWORD);
 
-                if let Some(t) = *term_delay_thr.lock().unwrap() {
-                    thread::park_timeout(t);
-                }
+                grace_period();
 
                 unsafe {
                     let handle = self::kernel32::OpenProcess(1, // PROCESS_TERMINATE
@@ -180,15 +1175,22 @@ impl Running {
         // This thread just does a wait() on the child, and stores the result
         // in a variable.
         let term_thr_timeout_handle = term_thr.clone();
+        let term_requested_wait_thr = term_requested.clone();
         let process_state_thr = process_state.clone();
+        let exit_status_thr = exit_status.clone();
+        let exit_time = Arc::new(Mutex::new(None));
+        let exit_time_thr = exit_time.clone();
         let wait_thr = thread::spawn(move || {
             // Finally, get the return code of the process.
             let &(ref lock, ref cvar) = &*child_result_thr;
             let mut child_result = lock.lock().unwrap();
 
-            let result = match child.wait() {
+            let result = child.wait();
+            *exit_time_thr.lock().unwrap() = Some(Instant::now());
+            let result = match result {
                 Err(_) => Some(-1),
                 Ok(o) => {
+                    *exit_status_thr.lock().unwrap() = Some(o);
                     match o.code() {
                         Some(c) => Some(c),
                         None => Some(-2),
@@ -201,24 +1203,109 @@ impl Running {
             cvar.notify_all();
 
             // Stop the timeout handle thread, which should exit immediately.
+            term_requested_wait_thr.store(true, Ordering::SeqCst);
             term_thr_timeout_handle.lock().unwrap().thread().unpark();
         });
 
+        let output_meter = Arc::new(Mutex::new(OutputMeter::default()));
+        let stdin_lines_written = Arc::new(AtomicU64::new(0));
+
         let stderr = match handles.remove("stderr") {
-            Some(s) => Some(RunningOutput { stream: s }),
+            Some(s) => {
+                Some(RunningOutput {
+                    stream: s,
+                    tee: None,
+                    meter: None,
+                    line_ending: None,
+                    pending: vec![],
+                    #[cfg(unix)]
+                    timeout: None,
+                })
+            }
             None => panic!("No stderr found"),
         };
 
+        #[cfg(unix)]
+        let fd_socket = handles.remove("fd_socket")
+            .map(|f| unsafe { UnixDatagram::from_raw_fd(f.into_raw_fd()) });
+        #[cfg(unix)]
+        let stdio_socket = handles.remove("stdio_socket")
+            .map(|f| unsafe { UnixStream::from_raw_fd(f.into_raw_fd()) });
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        let syscall_keys: Vec<String> = handles.keys().filter(|k| k.starts_with("syscall:")).cloned().collect();
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        let syscall_counters = syscall_keys.into_iter()
+            .map(|key| {
+                let counter = handles.remove(&key).unwrap();
+                (key["syscall:".len()..].to_owned(), counter)
+            })
+            .collect();
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        let perf_files = (0..).map_while(|i| handles.remove(&format!("perf:{}", i))).collect();
+
         Running {
             child_pid: child_pid,
+            workload_pid: child_pid,
             term_delay: term_delay,
-            input: Some(RunningInput { stream: input }),
-            output: Some(RunningOutput { stream: output }),
+            default_term_delay: None,
+            input: Some(RunningInput {
+                stream: input,
+                filter: None,
+                line_ending: None,
+                lines_written: stdin_lines_written.clone(),
+                #[cfg(unix)]
+                timeout: None,
+            }),
+            output: Some(RunningOutput {
+                stream: output,
+                tee: None,
+                meter: Some(output_meter.clone()),
+                line_ending: None,
+                pending: vec![],
+                #[cfg(unix)]
+                timeout: None,
+            }),
+            output_meter,
+            stdin_lines_written,
             error: stderr,
             term_thr: term_thr,
+            term_requested,
+            deadline,
             wait_thr: wait_thr,
             result: child_result,
+            exit_status,
+            #[cfg(unix)]
+            fd_socket,
+            #[cfg(unix)]
+            stdio_socket,
+            #[cfg(unix)]
+            pty: false,
+            #[cfg(unix)]
+            socket_stdio: false,
+            #[cfg(unix)]
+            notify_socket: None,
+            start_time,
+            exit_time,
+            kill_on_drop: true,
+            #[cfg(unix)]
+            frozen: AtomicBool::new(false),
+            #[cfg(unix)]
+            signal_forwarder: None,
+            attempt_exit_codes: vec![],
+            line_buffer: None,
+            script_delay: Duration::from_secs(0),
+            command: String::new(),
+            #[cfg(target_os = "linux")]
+            child_comm,
+            #[cfg(target_os = "linux")]
+            child_cwd,
             state: process_state,
+            #[cfg(all(target_os = "linux", feature = "perf"))]
+            syscall_counters,
+            #[cfg(all(target_os = "linux", feature = "perf"))]
+            perf_files,
+            #[cfg(all(target_os = "linux", feature = "perf"))]
+            perf_counters: vec![],
         }
     }
 
@@ -227,26 +1314,1136 @@ impl Running {
         value.unwrap()
     }
 
-    pub fn output(&self) -> &Option<RunningOutput> {
-        &self.output
+    /// Take both stdout and stderr, merged into one stream by a pair of
+    /// threads that copy whatever each one produces as it arrives.
+    pub fn take_combined_output(&mut self) -> Result<CombinedOutput> {
+        if self.output.is_none() || self.error.is_none() {
+            return Err(io::Error::from_raw_os_error(9 /* EBADF */));
+        }
+        let (reader, writer) = io::pipe()?;
+        let writer = Arc::new(Mutex::new(writer));
+
+        for source in [self.take_output(), self.take_error()] {
+            let mut source = UntilEof(source);
+            let writer = writer.clone();
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    match source.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if writer.lock().unwrap().write_all(&buf[..n]).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+        Ok(CombinedOutput { stream: reader })
     }
 
-    pub fn take_input(&mut self) -> RunningInput {
+    /// How many bytes of stdout can be read right now without blocking.
+    #[cfg(unix)]
+    pub fn output_bytes_available(&self) -> Result<usize> {
+        match self.output {
+            Some(ref o) => o.bytes_available(),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    /// How many bytes of stderr can be read right now without blocking.
+    #[cfg(unix)]
+    pub fn error_bytes_available(&self) -> Result<usize> {
+        match self.error {
+            Some(ref e) => e.bytes_available(),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    /// Whether stdout becomes readable within `timeout`, so that a read
+    /// won't block. End-of-file counts as readable.
+    #[cfg(unix)]
+    pub fn stdout_ready_timeout(&self, timeout: Duration) -> bool {
+        match self.output {
+            Some(ref o) => o.ready_timeout(timeout),
+            None => false,
+        }
+    }
+
+    /// Whether stderr becomes readable within `timeout`.
+    #[cfg(unix)]
+    pub fn stderr_ready_timeout(&self, timeout: Duration) -> bool {
+        match self.error {
+            Some(ref e) => e.ready_timeout(timeout),
+            None => false,
+        }
+    }
+
+    /// Copy everything subsequently read from stdout into the file at `path`.
+    pub fn tee_output_to<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let output = match self.output {
+            Some(ref mut s) => s,
+            None => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        };
+        output.tee = Some(File::create(path)?);
+        Ok(())
+    }
+
+    /// Point the child's stdout at the file at `path`, appending to it or
+    /// truncating it, as a service manager does to reopen logs after
+    /// rotating them.  The child is briefly stopped with ptrace and made to
+    /// open() the file and dup2() it over its fd 1 itself.  From then on its
+    /// output doesn't come through the pty, so can't be read from the
+    /// Running, and it keeps going to the file after this process exits.
+    ///
+    /// The file is opened by the child, with its permissions and umask.  A
+    /// relative `path` is taken from this process's working directory.  The
+    /// tracing is done by a separate process, which isn't the child's
+    /// ancestor, so where Yama's ptrace_scope is 1 or more this needs
+    /// CAP_SYS_PTRACE.  A syscall the child was blocked in is restarted.
+    /// Output it has buffered itself ends up wherever it's next flushed to,
+    /// and anything it has dup()ed from stdout keeps going to the old place.
+    /// Only x86_64 is supported.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", feature = "ptrace"))]
+    pub fn redirect_stdout_to_file(&self, path: &Path, append: bool) -> Result<()> {
+        use self::nix::libc;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut path = std::env::current_dir()?.join(path).as_os_str().as_bytes().to_vec();
+        if path.contains(&0) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        path.push(0);
+        let flags = libc::O_WRONLY | libc::O_CREAT | if append { libc::O_APPEND } else { libc::O_TRUNC };
+        if *self.state.lock().unwrap() == ProcessState::Exited {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        reopen_fd_with_ptrace(self.workload_pid, &path, flags, 1)
+    }
+
+    /// Take stdout and call `f` with each chunk of it as it arrives, on a
+    /// new thread that finishes once the output is closed.  Fails with EBADF
+    /// if stdout was already taken.
+    pub fn watch_output<F>(&mut self, f: F) -> Result<JoinHandle<()>>
+        where F: Fn(&[u8]) + Send + 'static
+    {
+        match self.output.take() {
+            Some(output) => Ok(Self::watch(output, f)),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    /// Take stdout and send each line of it, without its newline, down the
+    /// returned channel as it arrives.  A line that isn't valid UTF-8 is sent
+    /// as an InvalidData error.  The channel closes when the output does.
+    pub fn stdout_to_channel_lines(&mut self) -> Receiver<Result<String>> {
+        let mut stream = BufReader::new(UntilEof(self.take_output()));
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                let mut line = vec![];
+                match stream.read_until(b'\n', &mut line) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        if line.last() == Some(&b'\n') {
+                            line.pop();
+                        }
+                        let line = String::from_utf8(line)
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        tx.send(Err(e)).ok();
+                        break;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    /// Take stdout and keep the last `capacity` lines of it in memory as they
+    /// arrive, for output_lines_since() to return.
+    pub fn buffer_output_lines(&mut self, capacity: usize) -> Result<()> {
+        let output = match self.output.take() {
+            Some(o) => o,
+            None => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        };
+        let buffer = Arc::new(Mutex::new(LineBuffer {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            total: 0,
+        }));
+        self.line_buffer = Some(buffer.clone());
+
+        let mut stream = BufReader::new(UntilEof(output));
+        thread::spawn(move || {
+            loop {
+                let mut line = vec![];
+                match stream.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        if line.last() == Some(&b'\n') {
+                            line.pop();
+                        }
+                        buffer.lock().unwrap().push(String::from_utf8_lossy(&line).into_owned());
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Return the lines of stdout that came after line number `last_line`,
+    /// along with the number of the last line so far, to pass in next time.
+    /// Lines are numbered from 1, so pass 0 to get everything.  Only the lines
+    /// buffered by buffer_output_lines() are available, which is called with
+    /// room for 1000 lines if it wasn't already.
+    pub fn output_lines_since(&mut self, last_line: u64) -> Result<(Vec<String>, u64)> {
+        if self.line_buffer.is_none() {
+            self.buffer_output_lines(DEFAULT_LINE_BUFFER)?;
+        }
+        let buffer = self.line_buffer.as_ref().unwrap().lock().unwrap();
+        let first_line = buffer.total - buffer.lines.len() as u64;
+        let skip = last_line.saturating_sub(first_line) as usize;
+        Ok((buffer.lines.iter().skip(skip).cloned().collect(), buffer.total))
+    }
+
+    /// Like watch_output(), but for stderr.
+    pub fn watch_error<F>(&mut self, f: F) -> Result<JoinHandle<()>>
+        where F: Fn(&[u8]) + Send + 'static
+    {
+        match self.error.take() {
+            Some(error) => Ok(Self::watch(error, f)),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    fn watch<F>(stream: RunningOutput, f: F) -> JoinHandle<()>
+        where F: Fn(&[u8]) + Send + 'static
+    {
+        let mut stream = UntilEof(stream);
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => f(&buf[..n]),
+                }
+            }
+        })
+    }
+
+    pub fn output(&self) -> &Option<RunningOutput> {
+        &self.output
+    }
+
+    pub fn take_input(&mut self) -> RunningInput {
         let stream = self.input.take();
         stream.unwrap()
     }
 
-    pub fn input(&self) -> &Option<RunningInput> {
-        &self.input
+    pub fn input(&self) -> &Option<RunningInput> {
+        &self.input
+    }
+
+    /// Write all of `data` to the child's input without taking it.
+    pub fn send_bytes_to_stdin(&mut self, data: &[u8]) -> Result<usize> {
+        match self.input.as_mut() {
+            Some(input) => input.write_all(data).map(|_| data.len()),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    /// Write all of `data` to the child's input and then close it.  Fails
+    /// with EBADF if input was already closed or taken.  The child only sees
+    /// end-of-file when its stdin is a socket (see Runny::with_stdio_sockets());
+    /// a pty stays open for as long as its output can still be read.
+    pub fn write_close_stdin(&mut self, data: &[u8]) -> Result<()> {
+        let mut input = match self.input.take() {
+            Some(i) => i,
+            None => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        };
+        input.write_all(data)?;
+        input.flush()?;
+        #[cfg(unix)]
+        {
+            if let Some(ref socket) = self.stdio_socket {
+                socket.shutdown(std::net::Shutdown::Write)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set how long write_script() waits between lines.
+    pub fn set_script_delay(&mut self, delay: Duration) {
+        self.script_delay = delay;
+    }
+
+    /// Write each of `lines` to the child's input, followed by a newline,
+    /// waiting for the delay set by set_script_delay() in between.
+    pub fn write_script(&mut self, lines: &[&str]) -> Result<()> {
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 && self.script_delay > Duration::from_secs(0) {
+                thread::sleep(self.script_delay);
+            }
+            self.send_bytes_to_stdin(format!("{}\n", line).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Drive an interactive program: wait for `prompt`, then write each of
+    /// `lines` in turn and wait for `prompt` again before writing the next.
+    /// Returns what was output in response to each line, leaving out the
+    /// prompt.  Fails with TimedOut if any prompt takes longer than `timeout`.
+    #[cfg(unix)]
+    pub fn write_script_and_wait_for(&mut self,
+                                     lines: &[&str],
+                                     prompt: &str,
+                                     timeout: Duration)
+                                     -> Result<Vec<String>> {
+        self.read_until_prompt(prompt, timeout)?;
+        let mut responses = vec![];
+        for line in lines {
+            self.send_bytes_to_stdin(format!("{}\n", line).as_bytes())?;
+            responses.push(self.read_until_prompt(prompt, timeout)?);
+        }
+        Ok(responses)
+    }
+
+    /// Read stdout up to and including `prompt`, returning what came before
+    /// it.  This reads a byte at a time, so that nothing after the prompt is
+    /// consumed.
+    #[cfg(unix)]
+    fn read_until_prompt(&mut self, prompt: &str, timeout: Duration) -> Result<String> {
+        let output = match self.output {
+            Some(ref mut o) => o,
+            None => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        };
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![];
+        while !buf.ends_with(prompt.as_bytes()) {
+            let mut byte = [0u8];
+            output.read_exact_timeout(&mut byte, deadline.saturating_duration_since(Instant::now()))?;
+            buf.push(byte[0]);
+        }
+        buf.truncate(buf.len() - prompt.len());
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Copy the contents of a file to the child's input, then close the input.
+    /// On Unix the pty master stays open for reading output, so closing the
+    /// input does not by itself deliver EOF to the child.
+    pub fn write_file_to_stdin(&mut self, path: &Path) -> Result<u64> {
+        let mut file = File::open(path)?;
+        let mut input = match self.input.take() {
+            Some(i) => i,
+            None => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        };
+        io::copy(&mut file, &mut input)
+    }
+
+    pub fn set_input_filter(&mut self, filter: InputFilter) {
+        if let Some(ref mut input) = self.input {
+            input.filter = Some(filter);
+        }
+    }
+
+    pub fn take_error(&mut self) -> RunningOutput {
+        let value = self.error.take();
+        value.unwrap()
+    }
+
+    pub fn error(&self) -> &Option<RunningOutput> {
+        &self.error
+    }
+
+    /// Replace the timeout, which is measured from when the process was
+    /// started.  If that time has already passed, the process is terminated.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.deadline.lock().unwrap() = Some(self.start_time + timeout);
+        self.term_thr.lock().unwrap().thread().unpark();
+    }
+
+    /// The parent's end of the socket set up by Runny::with_fd_passing().
+    #[cfg(unix)]
+    pub fn fd_passing_socket(&self) -> Option<&UnixDatagram> {
+        self.fd_socket.as_ref()
+    }
+
+    /// Note that the child's stdio is connected to the socket given to
+    /// Runny::with_stdio_from_unix_socket().
+    #[cfg(unix)]
+    pub(crate) fn set_socket_stdio(&mut self) {
+        self.socket_stdio = true;
+    }
+
+    /// Note that the child's input and output are the master side of a pty.
+    #[cfg(unix)]
+    pub(crate) fn set_pty(&mut self) {
+        self.pty = true;
+    }
+
+    /// The pty master, from whichever of the input and output is still held.
+    /// There's no separate handle on it, so that dropping both still hangs up
+    /// the child.
+    #[cfg(unix)]
+    fn pty_fd(&self) -> Result<RawFd> {
+        if !self.pty {
+            return Err(io::Error::from_raw_os_error(self::nix::libc::ENOTTY));
+        }
+        self.input.as_ref().map(|input| input.stream.as_raw_fd())
+            .or_else(|| self.output.as_ref().map(|output| output.stream.as_raw_fd()))
+            .ok_or_else(|| io::Error::from_raw_os_error(9 /* EBADF */))
+    }
+
+    /// Whether the child's stdio is connected to the socket given to
+    /// Runny::with_stdio_from_unix_socket(), rather than to this Running.
+    #[cfg(unix)]
+    pub fn has_socket_stdio(&self) -> bool {
+        self.socket_stdio
+    }
+
+    /// Take the socket set up by Runny::with_stdio_sockets(), which reads from
+    /// the child's stdout and writes to its stdin.
+    #[cfg(unix)]
+    pub fn take_io(&mut self) -> Result<RunningSocket> {
+        match self.stdio_socket.take() {
+            Some(stream) => Ok(RunningSocket { stream }),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    /// Keep track of the `logger` process the child's stderr is sent to.
+    /// It should exit by itself once the child has gone and it sees EOF,
+    /// but is killed if it hasn't done so shortly afterwards.
+    #[cfg(unix)]
+    pub(crate) fn set_log_process(&self, mut logger: Child) {
+        let waiter = self.waiter();
+        thread::spawn(move || {
+            waiter.wait();
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while Instant::now() < deadline {
+                if let Ok(Some(_)) = logger.try_wait() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            logger.kill().ok();
+            logger.wait().ok();
+        });
+    }
+
+    /// Give the process `delay` to exit after SIGTERM whenever no delay is
+    /// passed to terminate(), including when the timeout expires.
+    pub(crate) fn set_graceful_shutdown_delay(&mut self, delay: Duration) {
+        self.default_term_delay = Some(delay);
+        *self.term_delay.lock().unwrap() = Some(delay);
+    }
+
+    /// Make reads from stdout and stderr, and writes to stdin, give up with
+    /// EAGAIN once they've blocked for longer than the given timeouts.
+    /// A socket gets SO_RCVTIMEO and SO_SNDTIMEO, while a pty is made
+    /// non-blocking and polled.
+    #[cfg(unix)]
+    pub(crate) fn set_stdio_timeout(&mut self, read: Duration, write: Duration) -> Result<()> {
+        use self::nix::fcntl::{fcntl, FcntlArg, OFlag, O_NONBLOCK};
+
+        if let Some(ref socket) = self.stdio_socket {
+            socket.set_read_timeout(Some(read))?;
+            socket.set_write_timeout(Some(write))?;
+        } else if let Some(ref mut input) = self.input {
+            let fd = input.stream.as_raw_fd();
+            let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(io::Error::other)?;
+            fcntl(fd, FcntlArg::F_SETFL(OFlag::from_bits_truncate(flags) | O_NONBLOCK))
+                .map_err(io::Error::other)?;
+            input.timeout = Some(write);
+            if let Some(ref mut output) = self.output {
+                output.timeout = Some(read);
+            }
+        }
+        if let Some(ref mut error) = self.error {
+            error.timeout = Some(read);
+        }
+        Ok(())
+    }
+
+    /// Read all of stdout and stderr into memory while waiting for the process
+    /// to exit, then have them read back from there.  Returns the exit code.
+    pub(crate) fn buffer_until_exit(&mut self) -> Result<i32> {
+        fn replay(data: Vec<u8>) -> Result<RunningOutput> {
+            let (reader, mut writer) = io::pipe()?;
+            thread::spawn(move || writer.write_all(&data));
+            #[cfg(unix)]
+            let stream = File::from(std::os::fd::OwnedFd::from(reader));
+            #[cfg(windows)]
+            let stream = File::from(std::os::windows::io::OwnedHandle::from(reader));
+            Ok(RunningOutput {
+                stream,
+                tee: None,
+                meter: None,
+                line_ending: None,
+                pending: vec![],
+                #[cfg(unix)]
+                timeout: None,
+            })
+        }
+
+        let (output, error) = self.collect_output()?.finish()?;
+        let exit_code = self.result();
+        self.output = Some(replay(output)?);
+        self.error = Some(replay(error)?);
+        Ok(exit_code)
+    }
+
+    pub(crate) fn set_attempt_exit_codes(&mut self, codes: Vec<i32>) {
+        self.attempt_exit_codes = codes;
+    }
+
+    /// The exit code of every attempt made by Runny::retries(), in order,
+    /// ending with this one.  Empty if retries weren't asked for.
+    pub fn attempt_exit_codes(&self) -> &[i32] {
+        &self.attempt_exit_codes
+    }
+
+    pub(crate) fn set_command(&mut self, command: String) {
+        self.command = command;
+    }
+
+    /// Translate line endings written to stdin and read from stdout, as set by
+    /// Runny::with_stdin_line_ending() and with_stdout_line_ending().
+    pub(crate) fn set_line_endings(&mut self, stdin: LineEnding, stdout: LineEnding) {
+        if let Some(ref mut input) = self.input {
+            input.set_line_ending(stdin);
+        }
+        if let Some(ref mut output) = self.output {
+            output.set_line_ending(stdout);
+        }
+    }
+
+    /// Pair up the counters opened for Runny::with_perf_events() with the
+    /// `events` they count, for perf_stats().
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub(crate) fn set_perf_events(&mut self, events: &[PerfEvent]) -> Result<()> {
+        if self.perf_files.len() != events.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "perf counters went missing"));
+        }
+        self.perf_counters = events.iter().cloned().zip(self.perf_files.drain(..)).collect();
+        Ok(())
+    }
+
+    /// Point pid() and the /proc readers at the command itself rather than
+    /// the init process waiting on it, which is the direct child when
+    /// running with Runny::with_container_init().  The command has already
+    /// been exec()ed by the time spawn() returns, since it holds on to the
+    /// status pipe until then.  If it has already gone, the init process is
+    /// left in its place.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn set_container_init(&mut self) {
+        let children = fs::read_to_string(format!("/proc/{0}/task/{0}/children", self.child_pid));
+        let pid = children.ok().and_then(|c| c.split_whitespace().next().and_then(|p| p.parse().ok()));
+        if let Some(pid) = pid {
+            self.workload_pid = pid;
+            self.child_comm = fs::read_to_string(format!("/proc/{}/comm", pid))
+                .ok()
+                .map(|comm| comm.trim_end().to_owned());
+            self.child_cwd = fs::read_link(format!("/proc/{}/cwd", pid)).ok();
+        }
+    }
+
+    /// Pass on each of `signals` to the child whenever this process receives
+    /// it, for as long as the Running is around.
+    #[cfg(unix)]
+    pub(crate) fn forward_signals(&mut self, signals: &[Signal]) -> Result<()> {
+        self.signal_forwarder = Some(SignalForwarder::new(self.workload_pid, signals)?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn set_notify_socket(&mut self, socket: UnixDatagram, path: PathBuf) {
+        self.notify_socket = Some((socket, path));
+    }
+
+    /// Wait for the child to send READY=1 to its $NOTIFY_SOCKET, as set up by
+    /// Runny::with_notify_socket(), and return every variable it has sent.
+    #[cfg(unix)]
+    pub fn wait_for_notify(&self,
+                           timeout: Duration)
+                           -> result::Result<HashMap<String, String>, NotifyError> {
+        let socket = match self.notify_socket {
+            Some((ref socket, _)) => socket,
+            None => return Err(NotifyError::NotConfigured),
+        };
+
+        let deadline = Instant::now() + timeout;
+        let mut vars = HashMap::new();
+        let mut buf = [0; 4096];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(NotifyError::TimedOut);
+            }
+            if *self.state.lock().unwrap() == ProcessState::Exited {
+                return Err(NotifyError::ProcessExited(self.result()));
+            }
+
+            // Wake up regularly to notice if the process has exited.
+            socket.set_read_timeout(Some((deadline - now).min(Duration::from_millis(100))))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in String::from_utf8_lossy(&buf[..len]).lines() {
+                if let Some((key, value)) = line.split_once('=') {
+                    vars.insert(key.to_owned(), value.to_owned());
+                }
+            }
+            if vars.get("READY").map(|v| v == "1").unwrap_or(false) {
+                return Ok(vars);
+            }
+        }
+    }
+
+    /// Wait until something accepts connections on `port` on localhost, e.g.
+    /// a server that the child is starting up.
+    pub fn wait_for_port(&self, port: u16, timeout: Duration) -> result::Result<(), WaitError> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let deadline = Instant::now() + timeout;
+        loop {
+            if *self.state.lock().unwrap() == ProcessState::Exited {
+                return Err(WaitError::ProcessExited(self.result()));
+            }
+            if TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_ok() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(WaitError::Timeout);
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(100)));
+        }
+    }
+
+    /// Wait up to `timeout` for the child to use less than `threshold` percent
+    /// of a CPU, averaged over the most recent `window`, such as once a server
+    /// has finished starting up.
+    #[cfg(target_os = "linux")]
+    pub fn wait_for_idle(&self,
+                         threshold: f32,
+                         window: Duration,
+                         timeout: Duration)
+                         -> result::Result<(), WaitError> {
+        let deadline = Instant::now() + timeout;
+        let interval = (window / 10).clamp(Duration::from_millis(10), Duration::from_millis(100));
+        let mut samples: VecDeque<(Instant, Duration)> = VecDeque::new();
+        loop {
+            let now = Instant::now();
+            let cpu = match self.cpu_time() {
+                Ok(cpu) => cpu,
+                Err(_) if *self.state.lock().unwrap() == ProcessState::Exited => {
+                    return Err(WaitError::ProcessExited(self.result()))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            samples.push_back((now, cpu));
+            while samples.len() > 1 && now.duration_since(samples[1].0) >= window {
+                samples.pop_front();
+            }
+
+            let (start, start_cpu) = samples[0];
+            let elapsed = now.duration_since(start);
+            if elapsed >= window {
+                let usage = (cpu - start_cpu).as_secs_f32() / elapsed.as_secs_f32() * 100.0;
+                if usage < threshold {
+                    return Ok(());
+                }
+            }
+            if now >= deadline {
+                return Err(WaitError::Timeout);
+            }
+            thread::sleep((deadline - now).min(interval));
+        }
+    }
+
+    /// How much CPU time the child has used so far, in user and system mode
+    /// combined.  This doesn't include its own children.  Fails once the
+    /// child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn cpu_time(&self) -> Result<Duration> {
+        let stat = String::from_utf8_lossy(&self.read_proc("stat")?).into_owned();
+        // Fields after the command name, which is in parentheses, start at 3,
+        // and utime and stime are fields 14 and 15.
+        let mut fields = stat.rsplit_once(')')
+            .map(|(_, fields)| fields.split_whitespace().skip(14 - 3))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "can't parse stat"))?;
+        let mut ticks = 0u64;
+        for _ in 0..2 {
+            ticks += fields.next()
+                .and_then(|f| f.parse::<u64>().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "can't parse stat"))?;
+        }
+        let ticks_per_sec = unsafe { self::nix::libc::sysconf(self::nix::libc::_SC_CLK_TCK) } as u64;
+        Ok(Duration::from_secs(ticks) / ticks_per_sec as u32)
+    }
+
+    /// Wait up to `timeout` for the process to exit, and terminate it if it
+    /// hasn't by then, as though its timeout had expired.
+    pub fn wait_timeout_or_kill(&self, timeout: Duration) -> result::Result<i32, RunningError> {
+        {
+            let end = Instant::now() + timeout;
+            let mut deadline = self.deadline.lock().unwrap();
+            match *deadline {
+                Some(d) if d <= end => (),
+                _ => *deadline = Some(end),
+            }
+        }
+        self.term_thr.lock().unwrap().thread().unpark();
+        self.wait()
+    }
+
+    pub fn time_since_start(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    /// How long ago the process exited, or None if it's still running.
+    pub fn time_since_exit(&self) -> Option<Duration> {
+        self.exit_time.lock().unwrap().map(|t| t.elapsed())
+    }
+
+    /// How long the process has been running, or how long it ran for if it
+    /// has already exited.
+    pub fn elapsed_wall_time(&self) -> Duration {
+        match *self.exit_time.lock().unwrap() {
+            Some(exit_time) => exit_time.duration_since(self.start_time),
+            None => self.start_time.elapsed(),
+        }
+    }
+
+    /// A short description of the process state, such as "pid=1234 running",
+    /// "pid=1234 exited(0)" or "pid=1234 killed(SIGTERM)".
+    pub fn status_string(&self) -> String {
+        let status = match *self.exit_status.lock().unwrap() {
+            Some(status) => status,
+            None if *self.state.lock().unwrap() == ProcessState::Exited => {
+                return format!("pid={} exited(-1)", self.child_pid)
+            }
+            None => return format!("pid={} running", self.child_pid),
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(sig) = status.signal() {
+                return match Signal::from_c_int(sig) {
+                    Ok(sig) => format!("pid={} killed({:?})", self.child_pid, sig),
+                    Err(_) => format!("pid={} killed({})", self.child_pid, sig),
+                };
+            }
+        }
+        format!("pid={} exited({})", self.child_pid, status.code().unwrap_or(-2))
+    }
+
+    /// The signal that killed the process, or None if it exited normally or
+    /// is still running.
+    #[cfg(unix)]
+    pub fn exit_signal(&self) -> Option<Signal> {
+        use std::os::unix::process::ExitStatusExt;
+
+        let status = (*self.exit_status.lock().unwrap())?;
+        status.signal().and_then(|sig| Signal::from_c_int(sig).ok())
+    }
+
+    /// Where the core dump was written, if the child dumped core.  The path is
+    /// worked out from /proc/sys/kernel/core_pattern, so this is None if the
+    /// pattern pipes the core to a program such as systemd-coredump, or
+    /// uses something that can't be known after the fact, or if there's no
+    /// file at the path it gives.  A relative pattern is taken to be relative
+    /// to the directory the child started in.
+    #[cfg(target_os = "linux")]
+    pub fn core_dump_path(&self) -> Result<Option<PathBuf>> {
+        use std::os::unix::process::ExitStatusExt;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let status = match *self.exit_status.lock().unwrap() {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+        let exit_time = self.exit_time.lock().unwrap().unwrap_or_else(Instant::now);
+        if !status.core_dumped() {
+            return Ok(None);
+        }
+        let pattern = fs::read_to_string("/proc/sys/kernel/core_pattern")?;
+        let pattern = pattern.trim_end();
+        if pattern.starts_with('|') {
+            return Ok(None);
+        }
+        let uses_pid = fs::read_to_string("/proc/sys/kernel/core_uses_pid")?.trim() == "1";
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")?;
+
+        // %t is when the core was dumped, which can only be narrowed down to
+        // around when the child was seen to exit.
+        let exited_at = (SystemTime::now() - exit_time.elapsed())
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        for time in exited_at.saturating_sub(1)..exited_at + 2 {
+            let path = match expand_core_pattern(pattern,
+                                                 uses_pid,
+                                                 self.workload_pid,
+                                                 status.signal().unwrap_or(0),
+                                                 time,
+                                                 hostname.trim_end(),
+                                                 self.child_comm.as_deref()) {
+                Some(path) => PathBuf::from(path),
+                None => return Ok(None),
+            };
+            let path = match self.child_cwd {
+                Some(ref cwd) => cwd.join(path),
+                None if path.is_absolute() => path,
+                None => return Ok(None),
+            };
+            if path.exists() {
+                return Ok(Some(path));
+            }
+            if !pattern.contains("%t") {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// How many bytes have been read from stdout so far.
+    pub fn total_output_bytes(&self) -> u64 {
+        self.output_meter.lock().unwrap().total
+    }
+
+    /// How many bytes have been read from stdout over the last second.
+    pub fn output_rate_bytes_per_sec(&self) -> f64 {
+        let mut meter = self.output_meter.lock().unwrap();
+        meter.expire(Instant::now());
+        meter.recent.iter().map(|&(_, n)| n as f64).sum()
+    }
+
+    /// How many newlines have been written to stdin so far, including
+    /// through a RunningInput that has since been taken.
+    pub fn stdin_lines_written(&self) -> u64 {
+        self.stdin_lines_written.load(Ordering::SeqCst)
+    }
+
+    /// Drain stdout, returning how many bytes it produced.
+    pub fn output_size(&mut self) -> Result<u64> {
+        let mut counter = DevNull::default();
+        io::copy(self, &mut counter)?;
+        Ok(counter.bytes)
+    }
+
+    /// Drain stdout, returning how many newlines it produced.
+    pub fn output_line_count(&mut self) -> Result<u64> {
+        let mut counter = DevNull::default();
+        io::copy(self, &mut counter)?;
+        Ok(counter.lines)
+    }
+
+    /// The same as output_line_count(), named to match output_words_count().
+    pub fn output_lines_count(&mut self) -> Result<u64> {
+        self.output_line_count()
+    }
+
+    /// Drain stdout, returning how many whitespace-separated words it produced.
+    pub fn output_words_count(&mut self) -> Result<u64> {
+        let mut counter = DevNull::default();
+        io::copy(self, &mut counter)?;
+        Ok(counter.words)
+    }
+
+    /// Drain stdout, returning the first line that isn't empty, if any.
+    pub fn first_output_line(&mut self) -> Result<Option<String>> {
+        let mut s = String::new();
+        self.read_to_string(&mut s)?;
+        Ok(s.lines().find(|line| !line.trim().is_empty()).map(String::from))
+    }
+
+    /// Drain stdout, returning the last line that isn't empty, if any.
+    pub fn last_output_line(&mut self) -> Result<Option<String>> {
+        let mut s = String::new();
+        self.read_to_string(&mut s)?;
+        Ok(s.lines().rev().find(|line| !line.trim().is_empty()).map(String::from))
+    }
+
+    /// Drain stdout, returning the lines that contain `pattern`.
+    pub fn output_lines_matching(&mut self, pattern: &str) -> Result<Vec<String>> {
+        let mut s = String::new();
+        self.read_to_string(&mut s)?;
+        Ok(s.lines().filter(|line| line.contains(pattern)).map(String::from).collect())
+    }
+
+    /// Drain stdout, returning the lines that match the regular expression `re`.
+    #[cfg(feature = "regex")]
+    pub fn output_lines_matching_re(&mut self, re: &str) -> Result<Vec<String>> {
+        let re = regex::Regex::new(re).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut s = String::new();
+        self.read_to_string(&mut s)?;
+        Ok(s.lines().filter(|line| re.is_match(line)).map(String::from).collect())
+    }
+
+    /// Drain stdout and compare it with `expected`, returning the first line
+    /// that differs, or None if they're the same.
+    pub fn output_diff(&mut self, expected: &[u8]) -> Result<Option<DiffResult>> {
+        let mut actual = vec![];
+        self.read_to_end(&mut actual)?;
+        if actual == expected {
+            return Ok(None);
+        }
+
+        let mut expected_lines = expected.split_inclusive(|&b| b == b'\n');
+        let mut actual_lines = actual.split_inclusive(|&b| b == b'\n');
+        let mut line = 1;
+        loop {
+            let (e, a) = (expected_lines.next().unwrap_or(b""), actual_lines.next().unwrap_or(b""));
+            if e != a {
+                return Ok(Some(DiffResult {
+                    line,
+                    expected: String::from_utf8_lossy(e).into_owned(),
+                    actual: String::from_utf8_lossy(a).into_owned(),
+                }));
+            }
+            line += 1;
+        }
+    }
+
+    /// Drain stdout and panic, naming the first line that differs, unless it
+    /// matches `expected`.
+    pub fn assert_output_eq(&mut self, expected: &[u8]) {
+        match self.output_diff(expected) {
+            Ok(None) => (),
+            Ok(Some(diff)) => {
+                panic!("output differs at line {}: expected {:?}, got {:?}",
+                       diff.line,
+                       diff.expected,
+                       diff.actual)
+            }
+            Err(e) => panic!("couldn't read output: {}", e),
+        }
+    }
+
+    /// Wait for the process to exit, and panic unless it exited with 0.  The
+    /// message includes the command line, the exit code, and stderr.  Output
+    /// is buffered while waiting, as with each of these, so it can still be
+    /// checked with assert_output_contains() afterwards.
+    pub fn assert_exit_success(&mut self) -> &mut Running {
+        self.assert_exit_code(0)
+    }
+
+    /// Wait for the process to exit, and panic if it exited with 0.
+    pub fn assert_exit_failure(&mut self) -> &mut Running {
+        let code = self.result_buffered();
+        if code == 0 {
+            let stderr = self.stderr_for_panic();
+            panic!("`{}` exited with 0, expected it to fail{}", self.command, stderr);
+        }
+        self
+    }
+
+    /// Wait for the process to exit, and panic unless it exited with
+    /// `expected`.
+    pub fn assert_exit_code(&mut self, expected: i32) -> &mut Running {
+        let code = self.result_buffered();
+        if code != expected {
+            let stderr = self.stderr_for_panic();
+            panic!("`{}` exited with {}, expected {}{}", self.command, code, expected, stderr);
+        }
+        self
+    }
+
+    /// Wait for the process to exit, and panic unless it was killed by a
+    /// signal.
+    #[cfg(unix)]
+    pub fn assert_signaled(&mut self) -> &mut Running {
+        let code = self.result_buffered();
+        if self.exit_signal().is_none() {
+            let stderr = self.stderr_for_panic();
+            panic!("`{}` wasn't killed by a signal, it exited with {}{}", self.command, code, stderr);
+        }
+        self
+    }
+
+    /// Drain stdout and panic unless it contains `pattern`.
+    pub fn assert_output_contains(&mut self, pattern: &str) -> &mut Running {
+        let mut s = String::new();
+        if let Err(e) = self.read_to_string(&mut s) {
+            panic!("couldn't read output: {}", e);
+        }
+        if !s.contains(pattern) {
+            panic!("output of `{}` doesn't contain {:?}: {:?}", self.command, pattern, s);
+        }
+        self
+    }
+
+    /// Wait for the process to exit while buffering its output, so that it
+    /// can't block on a full pipe or pty in the meantime.
+    fn result_buffered(&mut self) -> i32 {
+        if self.output.is_some() && self.error.is_some() {
+            if let Ok(code) = self.buffer_until_exit() {
+                return code;
+            }
+        }
+        self.result()
+    }
+
+    /// Whatever is left of stderr, to add to a panic message.
+    fn stderr_for_panic(&mut self) -> String {
+        let mut stderr = vec![];
+        if let Some(ref mut error) = self.error {
+            error.read_to_end(&mut stderr).ok();
+        }
+        if stderr.is_empty() {
+            String::new()
+        } else {
+            format!(", with stderr:\n{}", String::from_utf8_lossy(&stderr))
+        }
+    }
+
+    /// Drain stdout, returning the SHA-256 hash of everything it produced.
+    #[cfg(feature = "hashing")]
+    pub fn output_hash(&mut self) -> Result<[u8; 32]> {
+        use sha2::{Digest, Sha256};
+
+        let mut hasher = Sha256::new();
+        io::copy(self, &mut hasher)?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Drain stdout and stderr on separate threads, so that a child which
+    /// fills one pipe while we block on the other can't deadlock, then wait
+    /// for it to exit.
+    pub fn wait_all_output(&mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let collector = self.collect_output()?;
+        let output = collector.finish()?;
+        self.wait().ok();
+        Ok(output)
     }
 
-    pub fn take_error(&mut self) -> RunningOutput {
-        let value = self.error.take();
-        value.unwrap()
+    /// Terminate the process, giving it `grace` to exit after SIGTERM, and
+    /// return whatever it wrote to stdout and stderr along with its exit code.
+    pub fn terminate_and_collect(&mut self,
+                                 grace: Option<Duration>)
+                                 -> result::Result<Output, RunningError> {
+        let collector = self.collect_output()?;
+        let exit_code = self.terminate(grace)?;
+        let (stdout, stderr) = collector.finish()?;
+        Ok(Output {
+            stdout,
+            stderr,
+            exit_code,
+        })
     }
 
-    pub fn error(&self) -> &Option<RunningOutput> {
-        &self.error
+    /// Mirrors Python's subprocess communicate(): write `input`, if given, to
+    /// stdin and then close it, while draining stdout and stderr, and wait
+    /// for the process to exit.  Returns stdout and stderr.  If the process
+    /// is still running after `timeout` it's terminated, and TimedOut holds
+    /// whatever it wrote up until then.  Giving `input` fails with
+    /// InvalidInput when stdin is a pty, since the child would never see EOF;
+    /// use Runny::with_stdio_sockets() for that.
+    pub fn communicate(&mut self,
+                       input: Option<&[u8]>,
+                       timeout: Option<Duration>)
+                       -> result::Result<(Vec<u8>, Vec<u8>), RunningError> {
+        #[cfg(unix)]
+        {
+            if input.is_some() && self.pty {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "stdin is a pty, which can't be closed").into());
+            }
+        }
+        match self.exchange(input.map(|i| i.to_vec()), timeout)? {
+            (output, false) => Ok((output.stdout, output.stderr)),
+            (output, true) => Err(RunningError::TimedOut(output)),
+        }
+    }
+
+    /// Write `input`, if given, to stdin on a new thread and then close it,
+    /// while draining stdout and stderr, and wait for the process to exit,
+    /// terminating it after `timeout` as with wait_timeout_or_kill().  Also
+    /// returns whether it was stopped by that or by its own timeout, rather
+    /// than exiting by itself.  A child that exits without reading all of
+    /// its input isn't treated as an error.
+    pub(crate) fn exchange(&mut self,
+                           input: Option<Vec<u8>>,
+                           timeout: Option<Duration>)
+                           -> Result<(Output, bool)> {
+        let collector = self.collect_output()?;
+        let writer = match input {
+            Some(input) => {
+                let mut stdin = match self.input.take() {
+                    Some(i) => i,
+                    None => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+                };
+                #[cfg(unix)]
+                let socket = self.stdio_socket.take();
+                Some(thread::spawn(move || {
+                    let result = stdin.write_all(&input).and_then(|_| stdin.flush());
+                    #[cfg(unix)]
+                    {
+                        if let Some(socket) = socket {
+                            socket.shutdown(std::net::Shutdown::Write).ok();
+                        }
+                    }
+                    match result {
+                        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                        r => r,
+                    }
+                }))
+            }
+            None => None,
+        };
+        if let Some(timeout) = timeout {
+            self.wait_timeout_or_kill(timeout).ok();
+        }
+
+        let (stdout, stderr) = collector.finish()?;
+        let exit_code = self.result();
+        let timed_out = match (*self.deadline.lock().unwrap(), *self.exit_time.lock().unwrap()) {
+            (Some(deadline), Some(exit_time)) => exit_time >= deadline,
+            _ => false,
+        };
+        if let Some(writer) = writer {
+            let result = writer.join().map_err(|_| io::Error::other("stdin writer panicked"))?;
+            // Input the child was killed before reading doesn't matter.
+            if !timed_out {
+                result?;
+            }
+        }
+        Ok((Output {
+                stdout,
+                stderr,
+                exit_code,
+            },
+            timed_out))
+    }
+
+    /// Start threads draining stdout and stderr into memory.
+    fn collect_output(&mut self) -> Result<OutputCollector> {
+        let (output, error) = match (self.output.take(), self.error.take()) {
+            (Some(o), Some(e)) => (o, e),
+            _ => return Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        };
+
+        fn drain(stream: RunningOutput) -> JoinHandle<Result<Vec<u8>>> {
+            thread::spawn(move || {
+                let mut buf = vec![];
+                UntilEof(stream).read_to_end(&mut buf).map(|_| buf)
+            })
+        }
+        Ok(OutputCollector {
+            output: drain(output),
+            error: drain(error),
+        })
     }
 
     pub fn wait(&self) -> result::Result<i32, RunningError> {
@@ -265,6 +2462,8 @@ impl Running {
             result: self.result.clone(),
             term_thr: self.term_thr.clone(),
             term_delay: self.term_delay.clone(),
+            default_term_delay: self.default_term_delay,
+            term_requested: self.term_requested.clone(),
         }
     }
 
@@ -282,6 +2481,12 @@ impl Running {
         ret.unwrap()
     }
 
+    /// The exit code, or None if the process is still running.  Unlike
+    /// result(), this never blocks.
+    pub fn try_result(&self) -> Option<i32> {
+        *self.result.0.lock().unwrap()
+    }
+
     pub fn terminate(&self, timeout: Option<Duration>) -> result::Result<i32, RunningError> {
 
         // If there's already a result, then the process has exited already.
@@ -297,9 +2502,10 @@ impl Running {
 
         // Set up the delay, then wake up the termination thread.
         if let Ok(ref mut delay) = self.term_delay.try_lock() {
-            **delay = timeout;
+            **delay = timeout.or(self.default_term_delay);
         }
 
+        self.term_requested.store(true, Ordering::SeqCst);
         self.term_thr.lock().unwrap().thread().unpark();
 
         // Hand execution off to self.wait(), which shouldn't block now that the process is
@@ -307,8 +2513,529 @@ impl Running {
         self.wait()
     }
 
+    /// Like terminate(), but don't wait for the process to go away.  Returns
+    /// the result if the process has already exited, and None otherwise, in
+    /// which case it will be terminated in the background.  Use wait() to
+    /// collect the result afterwards.
+    pub fn try_terminate(&self, timeout: Option<Duration>) -> Option<result::Result<i32, RunningError>> {
+        {
+            let (ref lock, _) = *self.result;
+            if let Ok(unlocked) = lock.try_lock() {
+                if let Some(retval) = *unlocked {
+                    return Some(Ok(retval));
+                }
+            }
+        }
+
+        if let Ok(mut delay) = self.term_delay.try_lock() {
+            *delay = timeout.or(self.default_term_delay);
+        }
+
+        self.term_requested.store(true, Ordering::SeqCst);
+        self.term_thr.lock().unwrap().thread().unpark();
+        None
+    }
+
+    /// Whether dropping this Running terminates the process, which it does
+    /// by default.  Otherwise the process is left to run on its own.
+    pub fn kill_on_drop(&mut self, flag: bool) -> &mut Running {
+        self.kill_on_drop = flag;
+        self
+    }
+
     pub fn pid(&self) -> i32 {
-        self.child_pid
+        self.workload_pid
+    }
+
+    /// Write the control character for `c` (e.g. 'c' for Ctrl-C) to the input.
+    /// The pty is in raw mode, so the child sees the byte itself rather than
+    /// a signal or EOF generated by the line discipline.
+    pub fn write_ctrl(&mut self, c: char) -> Result<()> {
+        let byte = match c.to_ascii_uppercase() {
+            '?' => 0x7f,
+            c @ '@'..='_' => c as u8 & 0x1f,
+            _ => return Err(io::Error::from_raw_os_error(22 /* EINVAL */)),
+        };
+        self.write_all(&[byte])
+    }
+
+    pub fn send_sigint(&mut self) -> Result<()> {
+        self.write_ctrl('c')
+    }
+
+    pub fn send_eof(&mut self) -> Result<()> {
+        self.write_ctrl('d')
+    }
+
+    pub fn send_sigtstp(&mut self) -> Result<()> {
+        self.write_ctrl('z')
+    }
+
+    /// Send a signal to every process in the child's process group.
+    #[cfg(unix)]
+    pub fn send_signal_group(&self, sig: Signal) -> result::Result<(), RunningError> {
+        kill(Pid::from_raw(-self.child_pid), sig)?;
+        Ok(())
+    }
+
+    /// Resize the child's terminal and tell it so by sending SIGWINCH to its
+    /// process group.  Fails if the child wasn't given a pty, or once both its
+    /// input and output have been taken.
+    #[cfg(unix)]
+    pub fn send_winch(&self, rows: u16, cols: u16) -> result::Result<(), RunningError> {
+        use self::nix::libc;
+
+        let pty = self.pty_fd()?;
+        let size = libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        if unsafe { libc::ioctl(pty, libc::TIOCSWINSZ, &size) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.send_signal_group(SIGWINCH)
+    }
+
+    /// Write the escape sequence for `key` straight to the pty, bypassing any
+    /// input filter or line ending translation.  Fails with ENOTTY if the
+    /// child isn't attached to a pty, and EBADF once the input has been taken
+    /// or closed.
+    #[cfg(unix)]
+    pub fn send_key(&self, key: Key) -> Result<()> {
+        if !self.pty {
+            return Err(io::Error::from_raw_os_error(self::nix::libc::ENOTTY));
+        }
+        match self.input {
+            Some(ref input) => (&input.stream).write_all(key.escape_sequence()),
+            None => Err(io::Error::from_raw_os_error(9 /* EBADF */)),
+        }
+    }
+
+    /// Open another handle on the slave side of the child's pty, such as for a
+    /// terminal emulator to use.  Fails if the child wasn't given a pty, or
+    /// once both its input and output have been taken.
+    #[cfg(unix)]
+    pub fn open_pts(&self) -> Result<File> {
+        use self::nix::libc;
+        use std::ffi::CStr;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let pty = self.pty_fd()?;
+        let mut buf = [0 as libc::c_char; 64];
+        let ret = unsafe { libc::ptsname_r(pty, buf.as_mut_ptr(), buf.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        let path = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned();
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+            .open(path)
+    }
+
+    /// Whether the child is currently stopped, e.g. by SIGSTOP or SIGTSTP.
+    #[cfg(target_os = "linux")]
+    pub fn is_stopped(&self) -> bool {
+        let status = match self.read_proc("status") {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        String::from_utf8_lossy(&status)
+            .lines()
+            .find(|l| l.starts_with("State:"))
+            .map(|l| l["State:".len()..].trim_start().starts_with('T'))
+            .unwrap_or(false)
+    }
+
+    /// The signals the child's main thread is currently blocking, read from
+    /// /proc.  Real-time signals are left out, since SigSet can't hold them.
+    /// Fails once the child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn signal_mask(&self) -> Result<SigSet> {
+        let status = String::from_utf8_lossy(&self.read_proc("status")?).into_owned();
+        let mask = status.lines()
+            .find_map(|l| l.strip_prefix("SigBlk:"))
+            .and_then(|m| u64::from_str_radix(m.trim(), 16).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "can't parse SigBlk"))?;
+        let mut set = SigSet::empty();
+        for signum in 1..64 {
+            if mask & (1 << (signum - 1)) != 0 {
+                if let Ok(sig) = Signal::from_c_int(signum) {
+                    set.add(sig);
+                }
+            }
+        }
+        Ok(set)
+    }
+
+    /// Resume a stopped process group.
+    #[cfg(unix)]
+    pub fn send_sigcont(&self) -> result::Result<(), RunningError> {
+        self.send_signal_group(SIGCONT)
+    }
+
+    /// Stop the process group with SIGSTOP, unless freeze() already has.
+    #[cfg(unix)]
+    pub fn freeze(&self) -> result::Result<(), RunningError> {
+        if self.frozen.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = self.send_signal_group(SIGSTOP);
+        if result.is_err() {
+            self.frozen.store(false, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Resume a process group stopped by freeze().
+    #[cfg(unix)]
+    pub fn thaw(&self) -> result::Result<(), RunningError> {
+        if !self.frozen.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.send_sigcont()
+    }
+
+    /// Restrict every thread of the child to run only on `cpu`.  Threads it
+    /// starts afterwards inherit this, but its own children are unaffected.
+    #[cfg(target_os = "linux")]
+    pub fn bind_to_cpu(&self, cpu: u32) -> result::Result<(), RunningError> {
+        use self::nix::sched::{sched_setaffinity, CpuSet};
+
+        let mut cpus = CpuSet::new();
+        cpus.set(cpu as usize)?;
+        let tids = self.child_threads()?;
+        if tids.is_empty() {
+            return Err(io::Error::from_raw_os_error(3 /* ESRCH */).into());
+        }
+        for tid in tids {
+            sched_setaffinity(Pid::from_raw(tid as i32), &cpus)?;
+        }
+        Ok(())
+    }
+
+    /// Read the child's environment from /proc.  This is the block of
+    /// strings it was given at exec time: variables it later sets or unsets
+    /// with setenv() don't show up, though edits made in place (such as by
+    /// inject_env()) do.  Fails once the child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn environ(&self) -> Result<HashMap<String, String>> {
+        let mut environ = HashMap::new();
+        for entry in self.read_proc("environ")?.split(|c| *c == 0) {
+            let entry = String::from_utf8_lossy(entry);
+            if let Some((key, value)) = entry.split_once('=') {
+                environ.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        Ok(environ)
+    }
+
+    /// Read a single variable from the child's environment, or None if it
+    /// isn't set.  Fails once the child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn environ_var(&self, name: &str) -> Result<Option<String>> {
+        let prefix = format!("{}=", name);
+        Ok(self.read_proc("environ")?
+            .split(|c| *c == 0)
+            .find(|entry| entry.starts_with(prefix.as_bytes()))
+            .map(|entry| String::from_utf8_lossy(&entry[prefix.len()..]).into_owned()))
+    }
+
+    /// The same as environ(), named for what it actually returns: the
+    /// environment the child was started with, not its current one.
+    #[cfg(target_os = "linux")]
+    pub fn environ_at_exec(&self) -> Result<HashMap<String, String>> {
+        self.environ()
+    }
+
+    /// The same as environ_var(), looking `name` up in the environment the
+    /// child was started with.
+    #[cfg(target_os = "linux")]
+    pub fn environ_at_exec_var(&self, name: &str) -> Result<Option<String>> {
+        self.environ_var(name)
+    }
+
+    /// The path of the binary the child is actually running, after PATH
+    /// lookup and symlinks are resolved.  Fails once the child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn child_exe_path(&self) -> Result<PathBuf> {
+        if *self.state.lock().unwrap() == ProcessState::Exited {
+            return Err(io::Error::from_raw_os_error(3 /* ESRCH */));
+        }
+        fs::read_link(format!("/proc/{}/exe", self.workload_pid))
+    }
+
+    /// The path of the binary the child is actually running.  Fails once the
+    /// child has exited.
+    #[cfg(target_os = "macos")]
+    pub fn child_exe_path(&self) -> Result<PathBuf> {
+        use self::nix::libc;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        if *self.state.lock().unwrap() == ProcessState::Exited {
+            return Err(io::Error::from_raw_os_error(3 /* ESRCH */));
+        }
+        let mut buf = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+        let len = unsafe {
+            libc::proc_pidpath(self.workload_pid, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32)
+        };
+        if len <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PathBuf::from(OsStr::from_bytes(&buf[..len as usize])))
+    }
+
+    /// Read the child's I/O totals from /proc/{pid}/io.  These cover the
+    /// child itself and not any of its own children.  Fails once the child
+    /// has exited.
+    #[cfg(target_os = "linux")]
+    pub fn io_counters(&self) -> Result<IoCounters> {
+        let io = String::from_utf8_lossy(&self.read_proc("io")?).into_owned();
+        let mut counters = IoCounters::default();
+        for line in io.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key, value.trim()),
+                None => continue,
+            };
+            let field = match key {
+                "rchar" => &mut counters.rchar,
+                "wchar" => &mut counters.wchar,
+                "read_bytes" => &mut counters.read_bytes,
+                "write_bytes" => &mut counters.write_bytes,
+                _ => continue,
+            };
+            *field = value.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(counters)
+    }
+
+    /// Overwrite the value of `key` in the child's environment, for debugging.
+    /// This writes straight into the environment block the child was started
+    /// with, through /proc/{pid}/mem, and so needs permission to ptrace it.
+    /// Only variables that were set at startup can be changed, and `value`
+    /// may not be longer than the original.  If the child has since called
+    /// setenv() or putenv() for the variable, it won't see the change.
+    #[cfg(all(target_os = "linux", feature = "ptrace"))]
+    pub fn inject_env(&self, key: &str, value: &str) -> Result<()> {
+        use std::io::{Seek, SeekFrom};
+
+        let stat = String::from_utf8_lossy(&self.read_proc("stat")?).into_owned();
+        // Fields after the command name, which is in parentheses, start at 3,
+        // and env_start is field 50.
+        let env_start: u64 = stat.rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(50 - 3))
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "can't parse stat"))?;
+
+        let prefix = format!("{}=", key);
+        let environ = self.read_proc("environ")?;
+        let mut offset = 0;
+        let mut found = None;
+        for entry in environ.split(|c| *c == 0) {
+            if entry.starts_with(prefix.as_bytes()) {
+                found = Some((offset + prefix.len(), entry.len() - prefix.len()));
+                break;
+            }
+            offset += entry.len() + 1;
+        }
+        let (offset, len) = found.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{} isn't set", key))
+            })?;
+        if value.len() > len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("value is longer than the {} bytes available", len)));
+        }
+
+        // Pad with NULs so that nothing of the old value is left over.
+        let mut buf = value.as_bytes().to_vec();
+        buf.resize(len, 0);
+        let mut mem = OpenOptions::new().write(true).open(format!("/proc/{}/mem", self.workload_pid))?;
+        mem.seek(SeekFrom::Start(env_start + offset as u64))?;
+        mem.write_all(&buf)
+    }
+
+    /// The IDs of the child's threads, read from /proc, or an empty list
+    /// once it has exited.
+    #[cfg(target_os = "linux")]
+    pub fn child_threads(&self) -> Result<Vec<u32>> {
+        if *self.state.lock().unwrap() == ProcessState::Exited {
+            return Ok(vec![]);
+        }
+        let entries = match fs::read_dir(format!("/proc/{}/task", self.workload_pid)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut tids = vec![];
+        for entry in entries {
+            if let Some(tid) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+                tids.push(tid);
+            }
+        }
+        tids.sort();
+        Ok(tids)
+    }
+
+    /// How many threads the child has, or 0 once it has exited.
+    #[cfg(target_os = "linux")]
+    pub fn child_thread_count(&self) -> Result<usize> {
+        self.child_threads().map(|tids| tids.len())
+    }
+
+    /// How many times the child has made each syscall since it called exec(),
+    /// leaving out those it hasn't made at all.  Threads and processes it
+    /// goes on to create are counted along with it.  Counting has to be turned on
+    /// beforehand with Runny::count_syscalls(), and the counts can still be
+    /// read after the child exits.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn syscall_count(&self) -> Result<HashMap<String, u64>> {
+        if self.syscall_counters.is_empty() {
+            return Err(io::Error::other("syscalls aren't being counted"));
+        }
+        let mut counts = HashMap::new();
+        for (name, counter) in &self.syscall_counters {
+            let mut buf = [0u8; 8];
+            (&*counter).read_exact(&mut buf)?;
+            let count = u64::from_ne_bytes(buf);
+            if count > 0 {
+                counts.insert(name.clone(), count);
+            }
+        }
+        Ok(counts)
+    }
+
+    /// The values of the counters set up by Runny::with_perf_events(), counted
+    /// since the child called exec(), and including any threads and
+    /// processes it created.  The values are final once the child has
+    /// exited.  Where there were more hardware counters asked for than the
+    /// CPU has, the kernel takes turns with them, and the values are scaled
+    /// up to estimate the total.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn perf_stats(&self) -> result::Result<HashMap<PerfEvent, u64>, RunningError> {
+        use std::convert::TryInto;
+
+        let mut stats = HashMap::new();
+        for (event, counter) in &self.perf_counters {
+            // The value, then the time enabled and the time running.
+            let mut buf = [0u8; 24];
+            (&*counter).read_exact(&mut buf)?;
+            let value = u64::from_ne_bytes(buf[0..8].try_into().unwrap());
+            let enabled = u64::from_ne_bytes(buf[8..16].try_into().unwrap());
+            let running = u64::from_ne_bytes(buf[16..24].try_into().unwrap());
+            let value = if running == 0 {
+                0
+            } else {
+                (value as u128 * enabled as u128 / running as u128) as u64
+            };
+            stats.insert(*event, value);
+        }
+        Ok(stats)
+    }
+
+    /// List the TCP sockets the child has open, by matching the socket inodes
+    /// in /proc/{pid}/fd against its view of /proc/net/tcp and tcp6.  Sockets
+    /// belonging to the child's own children aren't included.  Fails once the
+    /// child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn netstat(&self) -> Result<Vec<NetConnection>> {
+        let mut inodes = vec![];
+        for entry in fs::read_dir(format!("/proc/{}/fd", self.workload_pid))? {
+            let target = match fs::read_link(entry?.path()) {
+                Ok(target) => target,
+                // The descriptor may have been closed since listing the directory.
+                Err(_) => continue,
+            };
+            let target = target.to_string_lossy();
+            if let Some(inode) = target.strip_prefix("socket:[").and_then(|s| s.strip_suffix(']')) {
+                inodes.push(inode.to_owned());
+            }
+        }
+
+        let mut connections = vec![];
+        for table in ["tcp", "tcp6"] {
+            let contents = match self.read_proc(&format!("net/{}", table)) {
+                Ok(contents) => contents,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in String::from_utf8_lossy(&contents).lines().skip(1) {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 10 || !inodes.iter().any(|i| i == fields[9]) {
+                    continue;
+                }
+                if let (Some(local_addr), Some(remote_addr)) =
+                    (parse_proc_net_addr(fields[1]), parse_proc_net_addr(fields[2])) {
+                    connections.push(NetConnection {
+                        local_addr,
+                        remote_addr,
+                        state: tcp_state_name(fields[3]),
+                    });
+                }
+            }
+        }
+        Ok(connections)
+    }
+
+    /// The memory limit of the child's cgroup, or u64::MAX if it has none.
+    /// Fails if the child isn't in a cgroup v2 hierarchy with the memory
+    /// controller enabled, or has exited.
+    #[cfg(target_os = "linux")]
+    pub fn memory_limit_bytes(&self) -> Result<u64> {
+        parse_memory_limit(&fs::read_to_string(self.cgroup_file("memory.max")?)?)
+    }
+
+    /// How many times the OOM killer has killed a process in the child's
+    /// memory cgroup, which may include the child's own children.  This is
+    /// read from memory.events under cgroup v2, or memory.oom_control under
+    /// cgroup v1.  Fails if the child has exited.
+    #[cfg(target_os = "linux")]
+    pub fn oom_kill_count(&self) -> Result<u64> {
+        let events = match self.cgroup_file("memory.events").and_then(fs::read_to_string) {
+            Ok(events) => events,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::read_to_string(self.cgroup_v1_file("memory", "memory.oom_control")?)?
+            }
+            Err(e) => return Err(e),
+        };
+        events.lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|count| count.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no oom_kill count"))
+    }
+
+    /// The path to the file `name` in the child's cgroup v2 directory.
+    #[cfg(target_os = "linux")]
+    fn cgroup_file(&self, name: &str) -> Result<PathBuf> {
+        let (cgroups, mountinfo) = self.cgroup_info()?;
+        cgroup_v2_path(&cgroups, &mountinfo, name)
+    }
+
+    /// The path to the file `name` in the child's cgroup v1 directory for
+    /// `controller`.
+    #[cfg(target_os = "linux")]
+    fn cgroup_v1_file(&self, controller: &str, name: &str) -> Result<PathBuf> {
+        let (cgroups, mountinfo) = self.cgroup_info()?;
+        cgroup_v1_path(&cgroups, &mountinfo, controller, name)
+    }
+
+    /// The child's /proc/{pid}/cgroup, and our own mountinfo to find the
+    /// hierarchies it names in.
+    #[cfg(target_os = "linux")]
+    fn cgroup_info(&self) -> Result<(String, String)> {
+        let cgroups = String::from_utf8_lossy(&self.read_proc("cgroup")?).into_owned();
+        Ok((cgroups, fs::read_to_string("/proc/self/mountinfo")?))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_proc(&self, name: &str) -> Result<Vec<u8>> {
+        if *self.state.lock().unwrap() == ProcessState::Exited {
+            return Err(io::Error::from_raw_os_error(3 /* ESRCH */));
+        }
+        fs::read(format!("/proc/{}/{}", self.workload_pid, name))
     }
 }
 
@@ -352,13 +3079,106 @@ impl Write for Running {
 
 impl Drop for Running {
     fn drop(&mut self) {
-        // Terminate immediately
-        self.terminate(None).ok();
+        if self.kill_on_drop {
+            // Terminate immediately
+            self.terminate(None).ok();
+        } else {
+            // Leave the process be, even if its timeout hasn't expired yet.
+            *self.deadline.lock().unwrap() = None;
+            self.term_thr.lock().unwrap().thread().unpark();
+        }
+
+        #[cfg(unix)]
+        {
+            if let Some((_, ref path)) = self.notify_socket {
+                fs::remove_file(path).ok();
+            }
+        }
+    }
+}
+
+impl RunningOutput {
+    /// Like read_exact(), but fails with TimedOut if `buf` can't be filled
+    /// within `timeout`.  Whatever was read before then is left in `buf`.
+    #[cfg(unix)]
+    pub fn read_exact_timeout(&mut self, mut buf: &mut [u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !buf.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match wait_ready(self.stream.as_raw_fd(), self::nix::poll::POLLIN, remaining) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut))
+                }
+                r => r?,
+            }
+            match self.read(buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => buf = &mut buf[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Translate line endings in what's read from now on.
+    pub fn set_line_ending(&mut self, mode: LineEnding) {
+        self.line_ending = LineEndingTranslator::new(mode);
+    }
+
+    /// How many bytes can be read right now without blocking.
+    #[cfg(unix)]
+    pub fn bytes_available(&self) -> Result<usize> {
+        use self::nix::libc;
+
+        let mut count: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.stream.as_raw_fd(), libc::FIONREAD, &mut count) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(count as usize)
+    }
+
+    /// Whether a read would return without blocking within `timeout`.
+    #[cfg(unix)]
+    pub fn ready_timeout(&self, timeout: Duration) -> bool {
+        !self.pending.is_empty()
+            || wait_ready(self.stream.as_raw_fd(), self::nix::poll::POLLIN, timeout).is_ok()
     }
 }
 
 impl Read for RunningOutput {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.line_ending.is_none() || buf.is_empty() {
+            return self.read_stream(buf);
+        }
+        while self.pending.is_empty() {
+            let mut raw = vec![0; buf.len()];
+            let n = self.read_stream(&mut raw)?;
+            let line_ending = self.line_ending.as_mut().unwrap();
+            if n == 0 {
+                self.pending = line_ending.finish();
+                if self.pending.is_empty() {
+                    return Ok(0);
+                }
+            } else {
+                self.pending = line_ending.translate(&raw[..n]);
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl RunningOutput {
+    fn read_stream(&mut self, buf: &mut [u8]) -> Result<usize> {
+        #[cfg(unix)]
+        {
+            if let Some(timeout) = self.timeout {
+                wait_ready(self.stream.as_raw_fd(), self::nix::poll::POLLIN, timeout)?;
+            }
+        }
         match self.stream.read(buf) {
             Err(e) => {
                 match e.raw_os_error() {
@@ -366,12 +3186,132 @@ impl Read for RunningOutput {
                     _ => Err(e),
                 }
             }
-            Ok(n) => Ok(n),
+            Ok(n) => {
+                if let Some(ref mut tee) = self.tee {
+                    tee.write_all(&buf[..n])?;
+                }
+                if let Some(ref meter) = self.meter {
+                    meter.lock().unwrap().record(n);
+                }
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Read for CombinedOutput {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl RunningInput {
+    /// Write `v` as a single byte.
+    pub fn write_u8(&mut self, v: u8) -> Result<()> {
+        self.write_all(&[v])
+    }
+
+    /// Write `v` as two little-endian bytes.
+    pub fn write_u16_le(&mut self, v: u16) -> Result<()> {
+        self.write_all(&v.to_le_bytes())
+    }
+
+    /// Write `v` as two big-endian bytes.
+    pub fn write_u16_be(&mut self, v: u16) -> Result<()> {
+        self.write_all(&v.to_be_bytes())
+    }
+
+    /// Write `v` as four little-endian bytes.
+    pub fn write_u32_le(&mut self, v: u32) -> Result<()> {
+        self.write_all(&v.to_le_bytes())
+    }
+
+    /// Write `v` as four big-endian bytes.
+    pub fn write_u32_be(&mut self, v: u32) -> Result<()> {
+        self.write_all(&v.to_be_bytes())
+    }
+
+    /// Translate line endings in what's written from now on.
+    pub fn set_line_ending(&mut self, mode: LineEnding) {
+        self.line_ending = LineEndingTranslator::new(mode);
+    }
+
+    fn write_stream(&mut self, buf: &[u8]) -> Result<usize> {
+        #[cfg(unix)]
+        {
+            if let Some(timeout) = self.timeout {
+                wait_ready(self.stream.as_raw_fd(), self::nix::poll::POLLOUT, timeout)?;
+            }
+        }
+        self.stream.write(buf)
+    }
+
+    fn write_all_stream(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write_stream(buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => buf = &buf[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
         }
+        Ok(())
+    }
+
+    /// Write out a "\r" that line ending translation is holding back in case
+    /// "\n" follows.
+    fn write_held(&mut self) -> Result<()> {
+        let held = match self.line_ending {
+            Some(ref mut line_ending) => line_ending.finish(),
+            None => return Ok(()),
+        };
+        self.write_all_stream(&held)
+    }
+}
+
+impl Drop for RunningInput {
+    fn drop(&mut self) {
+        self.write_held().ok();
     }
 }
 
 impl Write for RunningInput {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = if self.filter.is_some() || self.line_ending.is_some() {
+            let mut filtered = match self.filter {
+                Some(ref filter) => (filter.lock().unwrap())(buf),
+                None => buf.to_vec(),
+            };
+            if let Some(ref mut line_ending) = self.line_ending {
+                filtered = line_ending.translate(&filtered);
+            }
+            self.write_all_stream(&filtered)?;
+            buf.len()
+        } else {
+            self.write_stream(buf)?
+        };
+        let lines = buf[..n].iter().filter(|&&b| b == b'\n').count() as u64;
+        self.lines_written.fetch_add(lines, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    /// Also writes out a trailing "\r" held back by Unix line ending
+    /// translation, so a "\n" written after a flush isn't joined to it.
+    fn flush(&mut self) -> Result<()> {
+        self.write_held()?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(unix)]
+impl Read for RunningSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Write for RunningSocket {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         self.stream.write(buf)
     }
@@ -399,9 +3339,10 @@ impl RunningWaiter {
     pub fn terminate(&self, timeout: &Option<Duration>) {
         let mut lock = self.term_delay.try_lock();
         if let Ok(ref mut delay) = lock {
-            **delay = *timeout;
+            **delay = timeout.or(self.default_term_delay);
         }
         drop(lock);
+        self.term_requested.store(true, Ordering::SeqCst);
         self.term_thr.lock().unwrap().thread().unpark();
     }
 }
@@ -0,0 +1,134 @@
+//! Stand-ins for `Runny` and `Running` that never spawn a process, for
+//! unit-testing code that is built on top of this crate.
+
+use std::io::{Cursor, Read, Result, Write};
+use std::result;
+use std::time::Duration;
+
+use RunnyError;
+use running::{Running, RunningError};
+
+/// What `Running` and `FakeRunning` have in common, so that code can be
+/// written against either, and be given a `FakeRunning` under test.
+pub trait Process: Read + Write {
+    /// Wait for the process to exit, and return its exit code.
+    fn wait(&self) -> result::Result<i32, RunningError>;
+
+    /// The exit code, once the process has exited.
+    fn result(&self) -> i32;
+
+    /// Stop the process, giving it `timeout` to exit before it's killed.
+    fn terminate(&self, timeout: Option<Duration>) -> result::Result<i32, RunningError>;
+
+    /// The process ID of the process.
+    fn pid(&self) -> i32;
+}
+
+impl Process for Running {
+    fn wait(&self) -> result::Result<i32, RunningError> {
+        Running::wait(self)
+    }
+
+    fn result(&self) -> i32 {
+        Running::result(self)
+    }
+
+    fn terminate(&self, timeout: Option<Duration>) -> result::Result<i32, RunningError> {
+        Running::terminate(self, timeout)
+    }
+
+    fn pid(&self) -> i32 {
+        Running::pid(self)
+    }
+}
+
+impl Process for FakeRunning {
+    fn wait(&self) -> result::Result<i32, RunningError> {
+        FakeRunning::wait(self)
+    }
+
+    fn result(&self) -> i32 {
+        FakeRunning::result(self)
+    }
+
+    fn terminate(&self, timeout: Option<Duration>) -> result::Result<i32, RunningError> {
+        FakeRunning::terminate(self, timeout)
+    }
+
+    fn pid(&self) -> i32 {
+        FakeRunning::pid(self)
+    }
+}
+
+/// Starts a `FakeRunning` in place of `Runny` starting a process.
+pub struct FakeRunny {
+    output: Vec<u8>,
+    exit_code: i32,
+}
+
+/// A process that has already written all of its output and exited.
+/// Anything written to it is kept, and can be inspected with `written()`.
+pub struct FakeRunning {
+    output: Cursor<Vec<u8>>,
+    input: Vec<u8>,
+    exit_code: i32,
+}
+
+impl FakeRunny {
+    /// Each process started will have `output` to be read from it, as if
+    /// it had written that to stdout, and will have exited with `exit_code`.
+    pub fn new(output: &str, exit_code: i32) -> FakeRunny {
+        FakeRunny {
+            output: output.as_bytes().to_vec(),
+            exit_code,
+        }
+    }
+
+    pub fn start(&self) -> result::Result<FakeRunning, RunnyError> {
+        Ok(FakeRunning {
+            output: Cursor::new(self.output.clone()),
+            input: vec![],
+            exit_code: self.exit_code,
+        })
+    }
+}
+
+impl FakeRunning {
+    pub fn wait(&self) -> result::Result<i32, RunningError> {
+        Ok(self.exit_code)
+    }
+
+    pub fn result(&self) -> i32 {
+        self.exit_code
+    }
+
+    pub fn terminate(&self, _timeout: Option<Duration>) -> result::Result<i32, RunningError> {
+        // The fake process has always exited already, just like a real
+        // process that quits before terminate() is called.
+        Ok(self.exit_code)
+    }
+
+    pub fn pid(&self) -> i32 {
+        0
+    }
+
+    pub fn written(&self) -> &[u8] {
+        &self.input
+    }
+}
+
+impl Read for FakeRunning {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.output.read(buf)
+    }
+}
+
+impl Write for FakeRunning {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.input.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}